@@ -6,7 +6,7 @@ extern crate log;
 
 extern crate error_chain;
 
-use electrs::{config::Config, errors::*, store::DBStore};
+use electrs::{config::Config, errors::*, index::RowFormat, store::DBStore};
 
 use error_chain::ChainedError;
 
@@ -17,7 +17,12 @@ fn run(config: Config) -> Result<()> {
             config.db_path
         );
     }
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ true);
+    let format = if config.extended_index {
+        RowFormat::Extended
+    } else {
+        RowFormat::Compact
+    };
+    let store = DBStore::open(&config.db_path, /*low_memory=*/ true, format)?;
     store.compact();
     Ok(())
 }
@@ -6,8 +6,14 @@ extern crate error_chain;
 extern crate log;
 
 use electrs::{
-    cache::BlockTxIDsCache, config::Config, daemon::Daemon, errors::*, fake::FakeStore,
-    index::Index, metrics::Metrics, signal::Waiter,
+    cache::BlockTxIDsCache,
+    config::Config,
+    daemon::Daemon,
+    errors::*,
+    fake::FakeStore,
+    index::{Index, RowFormat},
+    metrics::Metrics,
+    signal::Waiter,
 };
 use error_chain::ChainedError;
 use std::sync::Arc;
@@ -22,15 +28,29 @@ fn run() -> Result<()> {
     let daemon = Daemon::new(
         &config.daemon_dir,
         &config.blocks_dir,
-        config.daemon_rpc_addr,
+        config.read_blk_files,
+        config.daemon_rpc_addrs.clone(),
         config.cookie_getter(),
+        config.daemon_timeouts,
+        config.daemon_rpc_connections,
         config.network_type,
         signal.clone(),
         cache,
         &metrics,
     )?;
     let fake_store = FakeStore {};
-    let index = Index::load(&fake_store, &daemon, &metrics, config.index_batch_size)?;
+    let format = if config.extended_index {
+        RowFormat::Extended
+    } else {
+        RowFormat::Compact
+    };
+    let index = Index::load(
+        &fake_store,
+        &daemon,
+        &metrics,
+        config.index_batch_size,
+        format,
+    )?;
     index.update(&fake_store, &signal)?;
     Ok(())
 }
@@ -3,7 +3,7 @@ extern crate electrs;
 extern crate hex;
 extern crate log;
 
-use electrs::{config::Config, store::DBStore};
+use electrs::{config::Config, index::RowFormat, store::DBStore};
 
 fn max_collision(store: DBStore, prefix: &[u8]) {
     let prefix_len = prefix.len();
@@ -40,7 +40,13 @@ fn run(config: Config) {
     if !config.db_path.exists() {
         panic!("DB {:?} must exist when running this tool!", config.db_path);
     }
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ false);
+    let format = if config.extended_index {
+        RowFormat::Extended
+    } else {
+        RowFormat::Compact
+    };
+    let store =
+        DBStore::open(&config.db_path, /*low_memory=*/ false, format).expect("failed to open DB");
     max_collision(store, b"T");
 }
 
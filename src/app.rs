@@ -1,13 +1,14 @@
 use bitcoin::hash_types::BlockHash;
-use std::sync::{Arc, Mutex};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
-use crate::{config::Config, daemon, errors::*, index, signal::Waiter, store};
+use crate::{daemon, errors::*, index, signal::Waiter, store};
 
 pub struct App {
     store: store::DBStore,
     index: index::Index,
     daemon: daemon::Daemon,
-    banner: String,
     tip: Mutex<BlockHash>,
 }
 
@@ -16,13 +17,11 @@ impl App {
         store: store::DBStore,
         index: index::Index,
         daemon: daemon::Daemon,
-        config: &Config,
     ) -> Result<Arc<App>> {
         Ok(Arc::new(App {
             store,
             index,
             daemon: daemon.reconnect()?,
-            banner: config.server_banner.clone(),
             tip: Mutex::new(BlockHash::default()),
         }))
     }
@@ -49,12 +48,53 @@ impl App {
         }
         Ok(new_block)
     }
+}
+
+fn read_banner_file(path: &Path) -> Result<String> {
+    fs::read_to_string(path).chain_err(|| format!("failed to read banner from {:?}", path))
+}
+
+/// The configured banner and donation address (served also during the initial sync).
+pub struct Banner {
+    template: RwLock<String>,
+    banner_file: Option<PathBuf>,
+    donation_address: Option<String>,
+}
+
+impl Banner {
+    /// The banner is read from `banner_file` (if specified), or is `server_banner` followed by
+    /// bitcoind's subversion.
+    pub fn new(
+        server_banner: &str,
+        banner_file: Option<PathBuf>,
+        donation_address: Option<String>,
+    ) -> Result<Banner> {
+        let template = match &banner_file {
+            Some(path) => read_banner_file(path)?,
+            None => format!("{}\n$DAEMON_SUBVERSION", server_banner),
+        };
+        Ok(Banner {
+            template: RwLock::new(template),
+            banner_file,
+            donation_address,
+        })
+    }
+
+    /// Returns the banner template (see `Backend::get_banner()` for the supported variables).
+    pub fn template(&self) -> String {
+        self.template.read().unwrap().clone()
+    }
+
+    /// Re-reads the banner file (if configured).
+    pub fn reload(&self) -> Result<()> {
+        if let Some(path) = &self.banner_file {
+            *self.template.write().unwrap() = read_banner_file(path)?;
+            info!("reloaded banner from {:?}", path);
+        }
+        Ok(())
+    }
 
-    pub fn get_banner(&self) -> Result<String> {
-        Ok(format!(
-            "{}\n{}",
-            self.banner,
-            self.daemon.get_subversion()?
-        ))
+    pub fn donation_address(&self) -> Option<&str> {
+        self.donation_address.as_ref().map(String::as_str)
     }
 }
@@ -4,25 +4,70 @@ extern crate error_chain;
 #[macro_use]
 extern crate log;
 
+use bitcoin::blockdata::constants::genesis_block;
 use error_chain::ChainedError;
 use std::process;
 use std::sync::Arc;
+use std::time::Instant;
 
 use electrs::{
-    app::App,
+    app::{App, Banner},
     bulk,
     cache::{BlockTxIDsCache, TransactionCache},
     config::Config,
     daemon::Daemon,
     errors::*,
-    index::Index,
+    index::{Index, RowFormat, SyncProgress},
     metrics::Metrics,
+    notify,
+    peers::PeerManager,
     query::Query,
-    rpc::RPC,
+    rpc::{server_features, Backend, RPC},
     signal::Waiter,
     store::{full_compaction, is_fully_compacted, DBStore},
+    zmq::{self, Notification, TOPIC_HASHBLOCK, TOPIC_RAWTX},
 };
 
+fn notification_handler(
+    query: &Arc<Query>,
+    signal: &Waiter,
+) -> impl Fn(Notification) + Send + Sync + 'static {
+    let query = Arc::clone(query);
+    let signal = signal.clone();
+    move |notification| match notification {
+        Notification::Block(blockhash) => {
+            debug!("notified about block {}", blockhash);
+            signal.trigger();
+        }
+        Notification::Transaction(tx) => {
+            if query.add_mempool_transaction(tx) {
+                signal.trigger(); // update subscribed clients
+            }
+        }
+    }
+}
+
+// Subscribes to bitcoind's ZMQ and P2P notifications (if configured).
+fn start_notifications(config: &Config, query: &Arc<Query>, signal: &Waiter) {
+    let mut endpoints = vec![];
+    if let Some(addr) = config.zmq_hashblock_addr {
+        endpoints.push((addr, TOPIC_HASHBLOCK));
+    }
+    if let Some(addr) = config.zmq_rawtx_addr {
+        endpoints.push((addr, TOPIC_RAWTX));
+    }
+    if !endpoints.is_empty() {
+        zmq::start(&endpoints, notification_handler(query, signal));
+    }
+    if let Some(addr) = config.p2p_addr {
+        notify::start(
+            addr,
+            config.network_type,
+            notification_handler(query, signal),
+        );
+    }
+}
+
 fn run_server(config: &Config) -> Result<()> {
     let signal = Waiter::start();
     let metrics = Metrics::new(config.monitoring_addr);
@@ -32,16 +77,65 @@ fn run_server(config: &Config) -> Result<()> {
     let daemon = Daemon::new(
         &config.daemon_dir,
         &config.blocks_dir,
-        config.daemon_rpc_addr,
+        config.read_blk_files,
+        config.daemon_rpc_addrs.clone(),
         config.cookie_getter(),
+        config.daemon_timeouts,
+        config.daemon_rpc_connections,
         config.network_type,
         signal.clone(),
         blocktxids_cache,
         &metrics,
     )?;
+    if config.reindex {
+        DBStore::destroy(&config.db_path);
+    }
+    let format = if config.extended_index {
+        RowFormat::Extended
+    } else {
+        RowFormat::Compact
+    };
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
-    let index = Index::load(&store, &daemon, &metrics, config.index_batch_size)?;
+    let store = DBStore::open(
+        &config.db_path,
+        /*low_memory=*/ config.jsonrpc_import,
+        format,
+    )?;
+    let index = Index::load(&store, &daemon, &metrics, config.index_batch_size, format)?;
+
+    // Accept connections during the initial sync (queries will fail until it's over).
+    let progress = Arc::new(SyncProgress::new(&metrics, &index));
+    progress.set_tip(daemon.get_tip_height()?);
+    let genesis_hash = genesis_block(config.network_type).block_hash();
+    let peers = PeerManager::new(
+        &config.db_path.join("peers.json"),
+        &config.peers,
+        genesis_hash,
+        server_features(&genesis_hash, &config.public_hosts),
+    );
+    PeerManager::start(&peers);
+    let backend = Backend::new(
+        daemon.reconnect()?,
+        Arc::clone(&progress),
+        peers,
+        Banner::new(
+            &config.server_banner,
+            config.banner_file.clone(),
+            config.donation_address.clone(),
+        )?,
+    );
+    let relayfee = daemon.get_relayfee()?;
+    debug!("relayfee: {} BTC", relayfee);
+    let server = RPC::start(
+        config.electrum_rpc_addr,
+        config.electrum_tls.as_ref(),
+        config.electrum_websocket_addr,
+        Arc::clone(&backend),
+        &metrics,
+        relayfee,
+        config.max_batch_size,
+    );
+
     let store = if is_fully_compacted(&store) {
         store // initial import and full compaction are over
     } else if config.jsonrpc_import {
@@ -49,35 +143,69 @@ fn run_server(config: &Config) -> Result<()> {
         full_compaction(store)
     } else {
         // faster, but uses more memory
-        let store =
-            bulk::index_blk_files(&daemon, config.bulk_index_threads, &metrics, &signal, store)?;
+        let store = bulk::index_blk_files(
+            &daemon,
+            config.bulk_index_threads,
+            &metrics,
+            &signal,
+            store,
+            format,
+            &progress,
+        )?;
         let store = full_compaction(store);
         index.reload(&store); // make sure the block header index is up-to-date
         store
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
 
-    let app = App::new(store, index, daemon, &config)?;
+    let app = App::new(store, index, daemon)?;
     let tx_cache = TransactionCache::new(config.tx_cache_size, &metrics);
     let query = Query::new(app.clone(), &metrics, tx_cache, config.txid_limit);
-    let relayfee = query.get_relayfee()?;
-    debug!("relayfee: {} BTC", relayfee);
+    let mempool_path = config.db_path.join("mempool.dat");
+    if let Err(e) = query.load_mempool(&mempool_path) {
+        warn!("failed to load mempool: {}", e.display_chain());
+    }
+    start_notifications(config, &query, &signal);
 
-    let mut server = None; // Electrum RPC server
-    loop {
-        app.update(&signal)?;
-        query.update_mempool()?;
-        server
-            .get_or_insert_with(|| {
-                RPC::start(config.electrum_rpc_addr, query.clone(), &metrics, relayfee)
-            })
-            .notify(); // update subscribed clients
-        if let Err(err) = signal.wait(config.wait_duration) {
-            info!("stopping server: {}", err);
-            break;
+    let mut last_mempool_update: Option<Instant> = None;
+    let mut serve = || -> Result<()> {
+        loop {
+            let new_block = app.update(&signal)?;
+            // Notified transactions are added incrementally, so the full mempool update (which
+            // also removes the evicted and confirmed transactions) can be done less frequently.
+            let incremental = config.zmq_rawtx_addr.is_some() || config.p2p_addr.is_some();
+            let mempool_update_due = last_mempool_update.map_or(true, |last| {
+                !incremental || last.elapsed() >= config.wait_duration
+            });
+            if new_block || mempool_update_due {
+                query.update_mempool()?;
+                last_mempool_update = Some(Instant::now());
+            }
+            if progress.is_syncing() {
+                info!("initial sync is over");
+                backend.set_query(Arc::clone(&query));
+            }
+            server.notify(); // update subscribed clients
+            signal.wait(config.wait_duration)?;
+            if signal.reload_requested() {
+                if let Err(e) = backend.reload_banner() {
+                    warn!("failed to reload banner: {}", e.display_chain());
+                }
+            }
+        }
+    };
+    let result = serve();
+    // the mempool is saved on every exit (e.g. also when an update fails)
+    if let Err(e) = query.save_mempool(&mempool_path) {
+        warn!("failed to save mempool: {}", e.display_chain());
+    }
+    if let Err(e) = &result {
+        if let ErrorKind::Interrupt(_) = e.kind() {
+            info!("stopping server: {}", e);
+            return Ok(());
         }
     }
-    Ok(())
+    result
 }
 
 fn main() {
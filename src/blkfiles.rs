@@ -0,0 +1,381 @@
+//! Locates blocks in bitcoind's blk*.dat files (by scanning their records), so new blocks and
+//! confirmed transactions can be read from disk instead of being fetched (as hex) via JSONRPC.
+//!
+//! The files are scanned in a background thread at startup (lookups fall back to JSONRPC until
+//! it's done), and re-scanned when a block is not found (e.g. since it was written afterwards).
+
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::{deserialize, Decodable, VarInt};
+use bitcoin::hash_types::{BlockHash, Txid};
+use error_chain::ChainedError;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::errors::*;
+use crate::metrics::{CounterVec, MetricOpts, Metrics};
+use crate::util::spawn_thread;
+
+const HEADER_SIZE: u64 = 80;
+
+pub fn list_blk_files(blocks_dir: &Path) -> Result<Vec<PathBuf>> {
+    let path = blocks_dir.join("blk*.dat");
+    let mut paths: Vec<PathBuf> = glob::glob(path.to_str().unwrap())
+        .chain_err(|| "failed to list blk*.dat files")?
+        .map(std::result::Result::unwrap)
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+#[derive(Clone, Copy)]
+struct Location {
+    file: usize, // index into `State::files`
+    offset: u64, // of the serialized block
+    size: u32,
+}
+
+struct BlkFile {
+    path: PathBuf,
+    scanned: u64,   // offset of the first record to be scanned
+    finished: bool, // bitcoind appends new blocks only to the last file
+}
+
+#[derive(Default)]
+struct State {
+    files: Vec<BlkFile>,
+    locations: HashMap<BlockHash, Location>,
+    missing: HashSet<BlockHash>, // not re-scanned for, until new blocks are located
+    ready: bool,                 // set after the initial scan
+}
+
+pub struct BlockFiles {
+    blocks_dir: PathBuf,
+    magic: u32,
+    state: Mutex<State>, // not locked during file I/O
+    scanning: Mutex<()>, // so the files are scanned by a single thread at a time
+    // monitoring
+    blocks: CounterVec,
+}
+
+fn read_u32(file: &mut File) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    file.read_exact(&mut buf).chain_err(|| "failed to read")?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Returns the blocks' locations, and the offset of the first unwritten record.
+/// Since bitcoind pre-allocates blk*.dat files (filling them with zeros), scanning stops
+/// at the first record with a wrong magic, or at a record that is not fully written yet.
+fn scan_file(path: &Path, start: u64, magic: u32) -> Result<(Vec<(BlockHash, u64, u32)>, u64)> {
+    let mut file = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+    let len = file
+        .metadata()
+        .chain_err(|| format!("failed to stat {:?}", path))?
+        .len();
+    let mut blocks = vec![];
+    let mut pos = start;
+    while pos + 8 + HEADER_SIZE <= len {
+        file.seek(SeekFrom::Start(pos))
+            .chain_err(|| format!("failed to seek {:?}", path))?;
+        if read_u32(&mut file)? != magic {
+            break;
+        }
+        let size = read_u32(&mut file)?;
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.read_exact(&mut header)
+            .chain_err(|| format!("failed to read header at {:?}:{}", path, pos))?;
+        // a record without a block body (see `bulk::parse_blocks`)
+        if u32::from_le_bytes(header[..4].try_into().unwrap()) == magic {
+            pos += 8;
+            continue;
+        }
+        let end = pos + 8 + u64::from(size);
+        if end > len {
+            break;
+        }
+        let header: BlockHeader =
+            deserialize(&header).chain_err(|| format!("invalid header at {:?}:{}", path, pos))?;
+        blocks.push((header.block_hash(), pos + 8, size));
+        pos = end;
+    }
+    Ok((blocks, pos))
+}
+
+impl BlockFiles {
+    fn new(blocks_dir: &Path, magic: u32, metrics: &Metrics) -> BlockFiles {
+        BlockFiles {
+            blocks_dir: blocks_dir.to_owned(),
+            magic,
+            state: Mutex::new(State::default()),
+            scanning: Mutex::new(()),
+            blocks: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrs_blk_file_blocks",
+                    "# of blocks (and transactions) looked up in blk*.dat files",
+                ),
+                &["result"],
+            ),
+        }
+    }
+
+    /// Scans the existing blk*.dat files in a background thread.
+    pub fn start(blocks_dir: &Path, magic: u32, metrics: &Metrics) -> Arc<BlockFiles> {
+        let blk_files = Arc::new(BlockFiles::new(blocks_dir, magic, metrics));
+        let scanner = Arc::clone(&blk_files);
+        spawn_thread("blk_files", move || scanner.initial_scan());
+        blk_files
+    }
+
+    fn initial_scan(&self) {
+        match self.scan() {
+            Ok(_) => {
+                let mut state = self.state.lock().unwrap();
+                info!("located {} blocks in blk*.dat files", state.locations.len());
+                state.ready = true;
+            }
+            Err(e) => warn!(
+                "failed to scan blk*.dat files (blocks will be fetched via JSONRPC): {}",
+                e.display_chain()
+            ),
+        }
+    }
+
+    /// Scans the new records (and files) since the previous scan, returning the # of new blocks.
+    fn scan(&self) -> Result<usize> {
+        let _guard = self.scanning.lock().unwrap();
+        let paths = list_blk_files(&self.blocks_dir)?;
+        let pending: Vec<(usize, PathBuf, u64)> = {
+            let mut state = self.state.lock().unwrap();
+            if state.files.is_empty() {
+                info!("locating blocks in {} blk*.dat files", paths.len());
+            }
+            for path in paths.iter().skip(state.files.len()) {
+                state.files.push(BlkFile {
+                    path: path.clone(),
+                    scanned: 0,
+                    finished: false,
+                });
+            }
+            state
+                .files
+                .iter()
+                .enumerate()
+                .filter(|(_, blk_file)| !blk_file.finished)
+                .map(|(index, blk_file)| (index, blk_file.path.clone(), blk_file.scanned))
+                .collect()
+        };
+        let mut located = 0;
+        for (index, path, start) in pending {
+            let (blocks, scanned) = scan_file(&path, start, self.magic)?;
+            let mut state = self.state.lock().unwrap();
+            let blk_file = &mut state.files[index];
+            blk_file.scanned = scanned;
+            blk_file.finished = index + 1 < paths.len();
+            located += blocks.len();
+            for (blockhash, offset, size) in blocks {
+                let location = Location {
+                    file: index,
+                    offset,
+                    size,
+                };
+                state.locations.insert(blockhash, location);
+            }
+        }
+        if located > 0 {
+            // the missing blocks may have been written since the last scan
+            self.state.lock().unwrap().missing.clear();
+        }
+        debug!("located {} new blocks", located);
+        Ok(located)
+    }
+
+    fn get_location(state: &State, blockhash: &BlockHash) -> Option<(PathBuf, Location)> {
+        state
+            .locations
+            .get(blockhash)
+            .map(|location| (state.files[location.file].path.clone(), *location))
+    }
+
+    fn locate(&self, blockhash: &BlockHash) -> Result<Option<(PathBuf, Location)>> {
+        {
+            let state = self.state.lock().unwrap();
+            if let Some(result) = Self::get_location(&state, blockhash) {
+                return Ok(Some(result));
+            }
+            if !state.ready || state.missing.contains(blockhash) {
+                return Ok(None);
+            }
+        }
+        self.scan()?; // the block may have been written after the last scan
+        let mut state = self.state.lock().unwrap();
+        let result = Self::get_location(&state, blockhash);
+        if result.is_none() {
+            state.missing.insert(*blockhash);
+        }
+        Ok(result)
+    }
+
+    /// Returns a reader for the block's transactions, after checking the block's header
+    /// (since it may have been overwritten, e.g. after `-reindex`).
+    fn open(
+        &self,
+        blockhash: &BlockHash,
+        path: &Path,
+        location: Location,
+    ) -> Result<(BlockHeader, impl Read)> {
+        let mut file = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+        file.seek(SeekFrom::Start(location.offset))
+            .chain_err(|| format!("failed to seek {:?}", path))?;
+        let mut reader = BufReader::new(file.take(u64::from(location.size)));
+        let header = BlockHeader::consensus_decode(&mut reader).chain_err(|| "invalid header")?;
+        if header.block_hash() != *blockhash {
+            bail!("unexpected block at {:?}:{}", path, location.offset);
+        }
+        Ok((header, reader))
+    }
+
+    fn read(&self, blockhash: &BlockHash, path: &Path, location: Location) -> Result<Block> {
+        let (header, mut reader) = self.open(blockhash, path, location)?;
+        let txdata = Vec::<Transaction>::consensus_decode(&mut reader)
+            .chain_err(|| format!("invalid block at {:?}:{}", path, location.offset))?;
+        let block = Block { header, txdata };
+        if block.merkle_root() != block.header.merkle_root {
+            bail!("unexpected transactions at {:?}:{}", path, location.offset);
+        }
+        Ok(block)
+    }
+
+    /// Decodes the block's transactions, until the requested one is found.
+    fn read_tx(
+        &self,
+        txid: &Txid,
+        blockhash: &BlockHash,
+        path: &Path,
+        location: Location,
+    ) -> Result<Transaction> {
+        let (_, mut reader) = self.open(blockhash, path, location)?;
+        let invalid = || format!("invalid block at {:?}:{}", path, location.offset);
+        let VarInt(count) = VarInt::consensus_decode(&mut reader).chain_err(invalid)?;
+        for _ in 0..count {
+            let tx = Transaction::consensus_decode(&mut reader).chain_err(invalid)?;
+            if tx.txid() == *txid {
+                return Ok(tx);
+            }
+        }
+        bail!(
+            "transaction {} is not found at {:?}:{}",
+            txid,
+            path,
+            location.offset
+        )
+    }
+
+    /// Returns `None` if the item can't be read from disk (so it should be fetched via JSONRPC).
+    fn lookup<T>(
+        &self,
+        blockhash: &BlockHash,
+        read: impl FnOnce(&Path, Location) -> Result<T>,
+    ) -> Option<T> {
+        let result = self.locate(blockhash).and_then(|location| {
+            location
+                .map(|(path, location)| read(&path, location))
+                .transpose()
+        });
+        match result {
+            Ok(Some(item)) => {
+                self.blocks.with_label_values(&["read"]).inc();
+                Some(item)
+            }
+            Ok(None) => {
+                debug!("block {} is not found in blk*.dat files", blockhash);
+                self.blocks.with_label_values(&["missing"]).inc();
+                None
+            }
+            Err(e) => {
+                warn!("failed to read block {}: {}", blockhash, e.display_chain());
+                self.blocks.with_label_values(&["failed"]).inc();
+                None
+            }
+        }
+    }
+
+    pub fn read_block(&self, blockhash: &BlockHash) -> Option<Block> {
+        self.lookup(blockhash, |path, location| {
+            self.read(blockhash, path, location)
+        })
+    }
+
+    pub fn read_transaction(&self, txid: &Txid, blockhash: &BlockHash) -> Option<Transaction> {
+        self.lookup(blockhash, |path, location| {
+            self.read_tx(txid, blockhash, path, location)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bulk::parse_blocks;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network;
+    use std::fs;
+
+    #[test]
+    fn test_read_blocks() {
+        let network = Network::Testnet;
+        let magic = network.magic();
+        let dir = std::env::temp_dir().join(format!("electrs-blkfiles-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        // contains a record without a block body, followed by 2 blocks
+        let fixture = Path::new("src/tests/fixtures/incomplete_block.hex");
+        let mut blob = hex::decode(fs::read_to_string(fixture).unwrap()).unwrap();
+        let blocks = parse_blocks(blob.clone(), magic).unwrap();
+        blob.extend_from_slice(&[0u8; 1000]); // pre-allocated space
+        fs::write(dir.join("blk00000.dat"), &blob).unwrap();
+
+        let metrics = Metrics::new(([127, 0, 0, 1], 0).into());
+        let blk_files = BlockFiles::new(&dir, magic, &metrics);
+        let blockhash = blocks[0].block_hash();
+        assert!(blk_files.read_block(&blockhash).is_none()); // before the initial scan
+        blk_files.initial_scan();
+        for block in &blocks {
+            let read = blk_files.read_block(&block.block_hash()).unwrap();
+            assert_eq!(serialize(&read), serialize(block));
+            for tx in &block.txdata {
+                let read = blk_files.read_transaction(&tx.txid(), &block.block_hash());
+                assert_eq!(read.as_ref(), Some(tx));
+            }
+        }
+        let other_txid = blocks[1].txdata[0].txid();
+        assert!(blk_files
+            .read_transaction(&other_txid, &blockhash)
+            .is_none());
+
+        let genesis = genesis_block(network);
+        assert!(blk_files.read_block(&genesis.block_hash()).is_none());
+
+        // a missing block is not re-scanned for, until new blocks are located
+        let mut records = vec![];
+        for block in &[genesis.clone(), genesis_block(Network::Bitcoin)] {
+            records.extend_from_slice(&magic.to_le_bytes());
+            records.extend_from_slice(&(serialize(block).len() as u32).to_le_bytes());
+            records.extend_from_slice(&serialize(block));
+        }
+        fs::write(dir.join("blk00001.dat"), &records).unwrap();
+        assert!(blk_files.read_block(&genesis.block_hash()).is_none());
+        let next = genesis_block(Network::Bitcoin);
+        assert_eq!(blk_files.read_block(&next.block_hash()), Some(next));
+        assert_eq!(blk_files.read_block(&genesis.block_hash()), Some(genesis));
+        assert!(blk_files.state.lock().unwrap().files[0].finished);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
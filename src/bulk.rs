@@ -13,7 +13,9 @@ use std::thread;
 
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use crate::index::{
+    index_block, last_indexed_block, read_indexed_blockhashes, RowFormat, SyncProgress,
+};
 use crate::metrics::{CounterVec, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::signal::Waiter;
 use crate::store::{DBStore, Row, WriteStore};
@@ -21,6 +23,7 @@ use crate::util::{spawn_thread, HeaderList, SyncChannel};
 
 struct Parser {
     magic: u32,
+    format: RowFormat,
     current_headers: HeaderList,
     indexed_blockhashes: Mutex<HashSet<BlockHash>>,
     // metrics
@@ -34,9 +37,11 @@ impl Parser {
         daemon: &Daemon,
         metrics: &Metrics,
         indexed_blockhashes: HashSet<BlockHash>,
+        format: RowFormat,
     ) -> Result<Arc<Parser>> {
         Ok(Arc::new(Parser {
             magic: daemon.magic(),
+            format,
             current_headers: load_headers(daemon)?,
             indexed_blockhashes: Mutex::new(indexed_blockhashes),
             duration: metrics.histogram_vec(
@@ -71,6 +76,12 @@ impl Parser {
         last_indexed_block(last_header.hash())
     }
 
+    // blk*.dat files are not ordered by height, so it's only an estimate
+    fn indexed_height(&self) -> usize {
+        let indexed_blockhashes = self.indexed_blockhashes.lock().unwrap();
+        indexed_blockhashes.len().saturating_sub(1)
+    }
+
     fn read_blkfile(&self, path: &Path) -> Result<Vec<u8>> {
         let timer = self.duration.with_label_values(&["read"]).start_timer();
         let blob = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
@@ -95,7 +106,7 @@ impl Parser {
                     .expect("indexed_blockhashes")
                     .insert(blockhash)
                 {
-                    rows.extend(index_block(&block, header.height()));
+                    rows.extend(index_block(&block, header.height(), self.format));
                     self.block_count.with_label_values(&["indexed"]).inc();
                 } else {
                     self.block_count.with_label_values(&["duplicate"]).inc();
@@ -114,7 +125,7 @@ impl Parser {
     }
 }
 
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
+pub(crate) fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
     let mut cursor = Cursor::new(&blob);
     let mut blocks = vec![];
     let max_pos = blob.len() as u64;
@@ -226,13 +237,18 @@ pub fn index_blk_files(
     metrics: &Metrics,
     signal: &Waiter,
     store: DBStore,
+    format: RowFormat,
+    progress: &SyncProgress,
 ) -> Result<DBStore> {
     set_open_files_limit(2048); // twice the default `ulimit -n` value
     let blk_files = daemon.list_blk_files()?;
     info!("indexing {} blk*.dat files", blk_files.len());
     let indexed_blockhashes = read_indexed_blockhashes(&store);
     debug!("found {} indexed blocks", indexed_blockhashes.len());
-    let parser = Parser::new(daemon, metrics, indexed_blockhashes)?;
+    let parser = Parser::new(daemon, metrics, indexed_blockhashes, format)?;
+    if let Some(tip_header) = parser.current_headers.iter().last() {
+        progress.set_tip_header(tip_header.clone());
+    }
     let (blobs, reader) = start_reader(blk_files, parser.clone());
     let rows_chan = SyncChannel::new(0);
     let indexers: Vec<JoinHandle> = (0..index_threads)
@@ -242,6 +258,7 @@ pub fn index_blk_files(
     for (rows, path) in rows_chan.into_receiver() {
         trace!("indexed {:?}: {} rows", path, rows.len());
         store.write(rows);
+        progress.set_height(parser.indexed_height());
         signal
             .poll()
             .chain_err(|| "stopping bulk indexing due to signal")?;
@@ -1,4 +1,5 @@
 use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::constants::genesis_block;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::{BlockHash, Txid};
@@ -6,19 +7,23 @@ use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::Hash;
 use bitcoin::network::constants::Network;
 use serde_json::{from_str, from_value, Map, Value};
-use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Lines, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::collections::HashSet;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
 use std::time::Duration;
 
+use crate::blkfiles::{self, BlockFiles};
 use crate::cache::BlockTxIDsCache;
 use crate::errors::*;
-use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
+use crate::http::{self, Endpoint, Timeouts};
+use crate::metrics::{Gauge, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::signal::Waiter;
-use crate::util::HeaderList;
+use crate::util::{spawn_thread, HeaderList};
+
+const MAX_TIP_LAG: u32 = 2; // blocks behind the best backend, before it's considered unhealthy
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 
 fn parse_hash<T: Hash>(value: &Value) -> Result<T> {
     Ok(T::from_hex(
@@ -52,6 +57,23 @@ fn tx_from_value(value: Value) -> Result<Transaction> {
     Ok(deserialize(&tx_bytes).chain_err(|| format!("failed to parse tx {}", tx_hex))?)
 }
 
+fn mempool_entry_from_value(entry: &Value) -> Result<MempoolEntry> {
+    let fee = entry
+        .get("fees")
+        .and_then(|fees| fees.get("base"))
+        .or_else(|| entry.get("fee")) // deprecated since bitcoind 0.19
+        .chain_err(|| "missing fee")?
+        .as_f64()
+        .chain_err(|| "non-float fee")?;
+    let vsize = entry
+        .get("size")
+        .or_else(|| entry.get("vsize")) // (https://github.com/bitcoin/bitcoin/pull/15637)
+        .chain_err(|| "missing vsize")?
+        .as_u64()
+        .chain_err(|| "non-integer vsize")? as u32;
+    Ok(MempoolEntry::new((fee * 100_000_000f64) as u64, vsize))
+}
+
 /// Parse JSONRPC error code, if exists.
 fn parse_error_code(err: &Value) -> Option<i64> {
     if err.is_null() {
@@ -147,123 +169,344 @@ pub trait CookieGetter: Send + Sync {
 }
 
 struct Connection {
-    tx: TcpStream,
-    rx: Lines<BufReader<TcpStream>>,
+    client: http::Client,
     cookie_getter: Arc<dyn CookieGetter>,
-    addr: SocketAddr,
-    signal: Waiter,
-}
-
-fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
-    loop {
-        match TcpStream::connect(addr) {
-            Ok(conn) => return Ok(conn),
-            Err(err) => {
-                warn!("failed to connect daemon at {}: {}", addr, err);
-                signal.wait(Duration::from_secs(3))?;
-                continue;
-            }
-        }
-    }
 }
 
 impl Connection {
+    // Connection failures are retried by `Daemon::retry()`, possibly using another backend.
     fn new(
-        addr: SocketAddr,
+        endpoint: &Endpoint,
         cookie_getter: Arc<dyn CookieGetter>,
-        signal: Waiter,
+        timeouts: Timeouts,
     ) -> Result<Connection> {
-        let conn = tcp_connect(addr, &signal)?;
-        let reader = BufReader::new(
-            conn.try_clone()
-                .chain_err(|| format!("failed to clone {:?}", conn))?,
-        );
         Ok(Connection {
-            tx: conn,
-            rx: reader.lines(),
+            client: http::Client::connect(endpoint.clone(), timeouts)?,
             cookie_getter,
-            addr,
-            signal,
         })
     }
 
-    fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
+    fn call(&mut self, request: &str) -> Result<String> {
+        let cookie = self.cookie_getter.get()?;
+        let authorization = format!("Basic {}", base64::encode(&cookie));
+        let headers = [
+            ("Authorization", authorization.as_str()),
+            ("Content-Type", "application/json"),
+        ];
+        let response = self.client.post("/", &headers, request.as_bytes())?;
+        let (status, reason) = (response.status, response.reason);
+        let contents = String::from_utf8(response.body).chain_err(|| "non UTF-8 reply")?;
+        Ok(match status {
+            200 => contents,
+            500 => {
+                warn!("HTTP status: {} {}", status, reason);
+                contents // the contents should have a JSONRPC error field
+            }
+            // e.g. when bitcoind's RPC work queue is full
+            503 => bail!(ErrorKind::Connection(format!(
+                "daemon is unavailable: {} {}",
+                status, reason
+            ))),
+            _ => bail!("request failed: {} {} = {:?}", status, reason, contents),
+        })
     }
+}
 
-    fn send(&mut self, request: &str) -> Result<()> {
-        let cookie = &self.cookie_getter.get()?;
-        let msg = format!(
-            "POST / HTTP/1.1\nAuthorization: Basic {}\nContent-Length: {}\n\n{}",
-            base64::encode(cookie),
-            request.len(),
-            request,
-        );
-        self.tx.write_all(msg.as_bytes()).chain_err(|| {
-            ErrorKind::Connection("disconnected from daemon while sending".to_owned())
-        })
+struct PoolState {
+    idle: Vec<Connection>,
+    open: usize,
+}
+
+/// Bitcoind JSONRPC connections, which are opened on demand (up to the pool size),
+/// so independent requests can proceed concurrently.
+struct ConnectionPool {
+    state: Mutex<PoolState>,
+    available: Condvar,
+    size: usize,
+    // used for opening new connections
+    endpoint: Endpoint,
+    cookie_getter: Arc<dyn CookieGetter>,
+    timeouts: Timeouts,
+    // monitoring
+    connections: Gauge,
+    busy: Gauge,
+    wait: Histogram,
+}
+
+impl ConnectionPool {
+    fn new(
+        endpoint: Endpoint,
+        cookie_getter: Arc<dyn CookieGetter>,
+        timeouts: Timeouts,
+        size: usize,
+        metrics: &Metrics,
+    ) -> ConnectionPool {
+        let label = endpoint.to_string();
+        ConnectionPool {
+            state: Mutex::new(PoolState {
+                idle: vec![],
+                open: 0,
+            }),
+            available: Condvar::new(),
+            size: size.max(1),
+            endpoint,
+            cookie_getter,
+            timeouts,
+            connections: metrics.gauge(
+                MetricOpts::new(
+                    "electrs_daemon_connections",
+                    "# of open bitcoind RPC connections",
+                )
+                .const_label("backend", label.as_str()),
+            ),
+            busy: metrics.gauge(
+                MetricOpts::new(
+                    "electrs_daemon_busy_connections",
+                    "# of bitcoind RPC connections in use",
+                )
+                .const_label("backend", label.as_str()),
+            ),
+            wait: metrics.histogram(
+                HistogramOpts::new(
+                    "electrs_daemon_connection_wait",
+                    "Waiting time for an available bitcoind RPC connection (in seconds)",
+                )
+                .const_label("backend", label.as_str()),
+            ),
+        }
     }
 
-    fn recv(&mut self) -> Result<String> {
-        // TODO: use proper HTTP parser.
-        let mut in_header = true;
-        let mut contents: Option<String> = None;
-        let iter = self.rx.by_ref();
-        let status = iter
-            .next()
-            .chain_err(|| {
-                ErrorKind::Connection("disconnected from daemon while receiving".to_owned())
-            })?
-            .chain_err(|| "failed to read status")?;
-        let mut headers = HashMap::new();
-        for line in iter {
-            let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
-            if line.is_empty() {
-                in_header = false; // next line should contain the actual response.
-            } else if in_header {
-                let parts: Vec<&str> = line.splitn(2, ": ").collect();
-                if parts.len() == 2 {
-                    headers.insert(parts[0].to_owned(), parts[1].to_owned());
-                } else {
-                    warn!("invalid header: {:?}", line);
+    fn get(&self) -> Result<Connection> {
+        let timer = self.wait.start_timer();
+        let mut state = self.state.lock().unwrap();
+        let conn = loop {
+            if let Some(conn) = state.idle.pop() {
+                break conn;
+            }
+            if state.open < self.size {
+                state.open += 1;
+                self.connections.set(state.open as i64);
+                drop(state); // don't block the other requests while connecting
+                let cookie_getter = Arc::clone(&self.cookie_getter);
+                match Connection::new(&self.endpoint, cookie_getter, self.timeouts) {
+                    Ok(conn) => break conn,
+                    Err(e) => {
+                        let mut state = self.state.lock().unwrap();
+                        state.open -= 1;
+                        self.connections.set(state.open as i64);
+                        self.available.notify_one();
+                        return Err(e);
+                    }
                 }
-            } else {
-                contents = Some(line);
-                break;
             }
+            state = self.available.wait(state).unwrap();
+        };
+        timer.observe_duration();
+        self.busy.inc();
+        Ok(conn)
+    }
+
+    fn put(&self, conn: Connection) {
+        self.state.lock().unwrap().idle.push(conn);
+        self.busy.dec();
+        self.available.notify_one();
+    }
+
+    fn call(&self, request: &str) -> Result<String> {
+        let mut conn = self.get()?;
+        let result = conn.call(request);
+        self.put(conn); // the connection is re-opened by the next request, if needed
+        result
+    }
+}
+
+/// A bitcoind node, which may serve the requests if it's healthy.
+struct Backend {
+    pool: ConnectionPool,
+    message_id: Counter, // for health check requests
+    // monitoring
+    healthy: Gauge,
+    height: Gauge,
+}
+
+impl Backend {
+    fn new(pool: ConnectionPool, metrics: &Metrics) -> Backend {
+        let label = pool.endpoint.to_string();
+        Backend {
+            pool,
+            message_id: Counter::new(),
+            healthy: metrics.gauge(
+                MetricOpts::new(
+                    "electrs_daemon_backend_healthy",
+                    "Whether the bitcoind backend passed its last health check",
+                )
+                .const_label("backend", label.as_str()),
+            ),
+            height: metrics.gauge(
+                MetricOpts::new(
+                    "electrs_daemon_backend_height",
+                    "Best block height of the bitcoind backend (at its last health check)",
+                )
+                .const_label("backend", label.as_str()),
+            ),
         }
+    }
 
-        let contents =
-            contents.chain_err(|| ErrorKind::Connection("no reply from daemon".to_owned()))?;
-        let contents_length: &str = headers
-            .get("Content-Length")
-            .chain_err(|| format!("Content-Length is missing: {:?}", headers))?;
-        let contents_length: usize = contents_length
-            .parse()
-            .chain_err(|| format!("invalid Content-Length: {:?}", contents_length))?;
-
-        let expected_length = contents_length - 1; // trailing EOL is skipped
-        if expected_length != contents.len() {
-            bail!(ErrorKind::Connection(format!(
-                "expected {} bytes, got {}",
-                expected_length,
-                contents.len()
-            )));
-        }
-
-        Ok(if status == "HTTP/1.1 200 OK" {
-            contents
-        } else if status == "HTTP/1.1 500 Internal Server Error" {
-            warn!("HTTP status: {}", status);
-            contents // the contents should have a JSONRPC error field
-        } else {
-            bail!(
-                "request failed {:?}: {:?} = {:?}",
-                status,
-                headers,
-                contents
+    fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.message_id.next();
+        let request = json!({"method": method, "params": params, "id": id});
+        let reply =
+            from_str(&self.pool.call(&request.to_string())?).chain_err(|| "invalid JSON")?;
+        parse_jsonrpc_reply(reply, method, id)
+    }
+
+    fn blockchain_info(&self) -> Result<(BlockHash, BlockchainInfo)> {
+        let genesis = parse_hash(&self.request("getblockhash", json!([0]))?)?;
+        let info = from_value(self.request("getblockchaininfo", json!([]))?)
+            .chain_err(|| "invalid blockchain info")?;
+        Ok((genesis, info))
+    }
+
+    /// Returns the backend's best block height, or `None` if it's temporarily unavailable
+    /// (e.g. unreachable or during IBD). Fails if the backend can't be used at all.
+    fn check(&self, network: Network) -> Result<Option<u32>> {
+        let (genesis, info) = match self.blockchain_info() {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("bitcoind at {} is unavailable: {}", self.pool.endpoint, e);
+                return Ok(None);
+            }
+        };
+        if genesis != genesis_block(network).block_hash() {
+            bail!("unexpected genesis block {} (not {:?})", genesis, network);
+        }
+        if info.pruned {
+            bail!("pruned node is not supported (use '-prune=0' bitcoind flag)");
+        }
+        if info.initialblockdownload
+            && !(network == Network::Regtest && info.headers == info.blocks)
+        {
+            warn!(
+                "bitcoind at {}: wait until IBD is over: headers={} blocks={} progress={}",
+                self.pool.endpoint, info.headers, info.blocks, info.verificationprogress
             );
-        })
+            return Ok(None);
+        }
+        self.height.set(i64::from(info.blocks));
+        Ok(Some(info.blocks))
+    }
+}
+
+/// Bitcoind nodes (in order of preference), which should follow the same chain.
+/// Requests are sent to the active backend, failing over to the next healthy one.
+struct Backends {
+    list: Vec<Backend>,
+    network: Network,
+    active: AtomicUsize,
+    switching: Mutex<()>, // so concurrent failures are handled once
+    active_index: Gauge,
+}
+
+impl Backends {
+    fn new(list: Vec<Backend>, network: Network, metrics: &Metrics) -> Backends {
+        Backends {
+            list,
+            network,
+            active: AtomicUsize::new(0),
+            switching: Mutex::new(()),
+            active_index: metrics.gauge(MetricOpts::new(
+                "electrs_daemon_active_backend",
+                "Index of the active bitcoind backend (0 is the primary one)",
+            )),
+        }
+    }
+
+    fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    fn endpoint(&self, index: usize) -> &Endpoint {
+        &self.list[index].pool.endpoint
+    }
+
+    /// Checks the backends (except the excluded one), returning the most preferred healthy one.
+    /// A backend whose tip lags behind the others is not considered healthy.
+    /// Fails if none of the backends can be used (e.g. all of them are pruned).
+    fn select(&self, exclude: Option<usize>) -> Result<Option<usize>> {
+        let mut errors = vec![];
+        let heights: Vec<Option<u32>> = self
+            .list
+            .iter()
+            .enumerate()
+            .map(|(index, backend)| {
+                if exclude == Some(index) {
+                    return None;
+                }
+                match backend.check(self.network) {
+                    Ok(height) => height,
+                    Err(e) => {
+                        warn!("bitcoind at {} can't be used: {}", backend.pool.endpoint, e);
+                        errors.push(e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        let best = heights.iter().flatten().max().cloned();
+        let is_healthy = |height: &Option<u32>| match (height, best) {
+            (Some(height), Some(best)) => height + MAX_TIP_LAG >= best,
+            _ => false,
+        };
+        for (backend, height) in self.list.iter().zip(heights.iter()) {
+            backend.healthy.set(is_healthy(height) as i64);
+        }
+        if errors.len() == self.list.len() {
+            return Err(errors.remove(0)).chain_err(|| "no usable bitcoind");
+        }
+        Ok(heights.iter().position(is_healthy))
+    }
+
+    fn activate(&self, index: usize) {
+        let prev = self.active.swap(index, Ordering::SeqCst);
+        if prev != index {
+            warn!(
+                "switched from bitcoind at {} to {}",
+                self.endpoint(prev),
+                self.endpoint(index)
+            );
+        }
+        self.active_index.set(index as i64);
+    }
+
+    /// Called after the given backend has failed, returning whether a healthy backend is active.
+    fn failover(&self, failed: usize) -> bool {
+        if self.list.len() == 1 {
+            return false;
+        }
+        let _guard = self.switching.lock().unwrap();
+        if self.active() != failed {
+            return true; // already switched by another request
+        }
+        match self.select(Some(failed)) {
+            Ok(Some(index)) => {
+                self.activate(index);
+                true
+            }
+            Ok(None) | Err(_) => false,
+        }
+    }
+
+    /// Periodically switches to the most preferred healthy backend (e.g. after it has recovered).
+    fn start_health_checks(backends: &Arc<Backends>) {
+        let backends = Arc::clone(backends);
+        spawn_thread("health_check", move || loop {
+            thread::sleep(HEALTH_CHECK_INTERVAL);
+            // the (possibly slow) checks shouldn't block failing over concurrently
+            if let Ok(Some(index)) = backends.select(None) {
+                let _guard = backends.switching.lock().unwrap();
+                backends.activate(index);
+            }
+        });
     }
 }
 
@@ -286,8 +529,9 @@ pub struct Daemon {
     daemon_dir: PathBuf,
     blocks_dir: PathBuf,
     network: Network,
-    conn: Mutex<Connection>,
-    message_id: Counter, // for monotonic JSONRPC 'id'
+    backends: Arc<Backends>,
+    blk_files: Option<Arc<BlockFiles>>, // for reading blocks without JSONRPC
+    message_id: Counter,                // for monotonic JSONRPC 'id'
     signal: Waiter,
     blocktxids_cache: Arc<BlockTxIDsCache>,
 
@@ -300,22 +544,50 @@ impl Daemon {
     pub fn new(
         daemon_dir: &PathBuf,
         blocks_dir: &PathBuf,
-        daemon_rpc_addr: SocketAddr,
+        read_blk_files: bool,
+        daemon_rpc_addrs: Vec<Endpoint>, // in order of preference
         cookie_getter: Arc<dyn CookieGetter>,
+        timeouts: Timeouts,
+        connections: usize,
         network: Network,
         signal: Waiter,
         blocktxids_cache: Arc<BlockTxIDsCache>,
         metrics: &Metrics,
     ) -> Result<Daemon> {
+        assert!(!daemon_rpc_addrs.is_empty(), "missing bitcoind endpoint");
+        let backends: Vec<Backend> = daemon_rpc_addrs
+            .into_iter()
+            .map(|endpoint| {
+                let cookie_getter = Arc::clone(&cookie_getter);
+                let pool =
+                    ConnectionPool::new(endpoint, cookie_getter, timeouts, connections, metrics);
+                Backend::new(pool, metrics)
+            })
+            .collect();
+        let backends = Arc::new(Backends::new(backends, network, metrics));
+        // make sure a healthy bitcoind is reachable before starting
+        loop {
+            if let Some(index) = backends.select(None)? {
+                backends.activate(index);
+                break;
+            }
+            warn!("waiting for a healthy bitcoind");
+            signal.wait(Duration::from_secs(3))?;
+        }
+        if backends.list.len() > 1 {
+            Backends::start_health_checks(&backends);
+        }
+        let blk_files = if read_blk_files {
+            Some(BlockFiles::start(blocks_dir, network.magic(), metrics))
+        } else {
+            None
+        };
         let daemon = Daemon {
             daemon_dir: daemon_dir.clone(),
             blocks_dir: blocks_dir.clone(),
             network,
-            conn: Mutex::new(Connection::new(
-                daemon_rpc_addr,
-                cookie_getter,
-                signal.clone(),
-            )?),
+            backends,
+            blk_files,
             message_id: Counter::new(),
             blocktxids_cache,
             signal: signal.clone(),
@@ -339,32 +611,17 @@ impl Daemon {
         }
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:?}", blockchain_info);
-        if blockchain_info.pruned {
-            bail!("pruned node is not supported (use '-prune=0' bitcoind flag)".to_owned())
-        }
-        loop {
-            let info = daemon.getblockchaininfo()?;
-            if !info.initialblockdownload {
-                break;
-            }
-            if network == Network::Regtest && info.headers == info.blocks {
-                break;
-            }
-            warn!(
-                "wait until IBD is over: headers={} blocks={} progress={}",
-                info.headers, info.blocks, info.verificationprogress
-            );
-            signal.wait(Duration::from_secs(3))?;
-        }
         Ok(daemon)
     }
 
+    /// Returns a new handle, sharing the backends (and their connection pools).
     pub fn reconnect(&self) -> Result<Daemon> {
         Ok(Daemon {
             daemon_dir: self.daemon_dir.clone(),
             blocks_dir: self.blocks_dir.clone(),
             network: self.network,
-            conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
+            backends: Arc::clone(&self.backends),
+            blk_files: self.blk_files.clone(),
             message_id: Counter::new(),
             signal: self.signal.clone(),
             blocktxids_cache: Arc::clone(&self.blocktxids_cache),
@@ -374,14 +631,11 @@ impl Daemon {
     }
 
     pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
-        let path = self.blocks_dir.join("blk*.dat");
-        info!("listing block files at {:?}", path);
-        let mut paths: Vec<PathBuf> = glob::glob(path.to_str().unwrap())
-            .chain_err(|| "failed to list blk*.dat files")?
-            .map(std::result::Result::unwrap)
-            .collect();
-        paths.sort();
-        Ok(paths)
+        info!(
+            "listing block files at {:?}",
+            self.blocks_dir.join("blk*.dat")
+        );
+        blkfiles::list_blk_files(&self.blocks_dir)
     }
 
     pub fn magic(&self) -> u32 {
@@ -389,14 +643,13 @@ impl Daemon {
     }
 
     fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
-        let mut conn = self.conn.lock().unwrap();
+        let backend = &self.backends.list[self.backends.active()];
         let timer = self.latency.with_label_values(&[method]).start_timer();
         let request = request.to_string();
-        conn.send(&request)?;
+        let response = backend.pool.call(&request)?;
         self.size
             .with_label_values(&[method, "send"])
             .observe(request.len() as f64);
-        let response = conn.recv()?;
         let result: Value = from_str(&response).chain_err(|| "invalid JSON")?;
         timer.observe_duration();
         self.size
@@ -405,31 +658,70 @@ impl Daemon {
         Ok(result)
     }
 
-    fn handle_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+    // Returns a result per request (e.g. some transactions may be missing from the mempool).
+    fn handle_request_batch(
+        &self,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Result<Value>>> {
         let id = self.message_id.next();
         let reqs = params_list
             .iter()
             .map(|params| json!({"method": method, "params": params, "id": id}))
             .collect();
-        let mut results = vec![];
         let mut replies = self.call_jsonrpc(method, &reqs)?;
         if let Some(replies_vec) = replies.as_array_mut() {
-            for reply in replies_vec {
-                results.push(parse_jsonrpc_reply(reply.take(), method, id)?)
-            }
-            return Ok(results);
+            return Ok(replies_vec
+                .iter_mut()
+                .map(|reply| parse_jsonrpc_reply(reply.take(), method, id))
+                .collect());
         }
         bail!("non-array replies: {:?}", replies);
     }
 
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        self.retry(|| {
+            self.handle_request_batch(method, params_list)?
+                .into_iter()
+                .collect()
+        })
+    }
+
+    // Daemon errors are returned as `None` (other errors fail the whole batch).
+    fn retry_request_batch_optional(
+        &self,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Option<Value>>> {
+        self.retry(|| {
+            self.handle_request_batch(method, params_list)?
+                .into_iter()
+                .map(|result| match result {
+                    Ok(value) => Ok(Some(value)),
+                    Err(Error(ErrorKind::Daemon(_, err), _)) => {
+                        debug!("{} failed: {}", method, err);
+                        Ok(None)
+                    }
+                    Err(e) => Err(e),
+                })
+                .collect()
+        })
+    }
+
+    fn retry<T>(&self, f: impl Fn() -> Result<T>) -> Result<T> {
         loop {
-            match self.handle_request_batch(method, params_list) {
+            let active = self.backends.active();
+            match f() {
                 Err(Error(ErrorKind::Connection(msg), _)) => {
-                    warn!("reconnecting to bitcoind: {}", msg);
-                    self.signal.wait(Duration::from_secs(3))?;
-                    let mut conn = self.conn.lock().unwrap();
-                    *conn = conn.reconnect()?;
+                    warn!(
+                        "bitcoind at {} failed: {}",
+                        self.backends.endpoint(active),
+                        msg
+                    );
+                    if !self.backends.failover(active) {
+                        warn!("reconnecting to bitcoind");
+                        self.signal.wait(Duration::from_secs(3))?;
+                    }
                     continue;
                 }
                 result => return result,
@@ -447,6 +739,10 @@ impl Daemon {
         self.retry_request_batch(method, params_list)
     }
 
+    fn requests_optional(&self, method: &str, params_list: &[Value]) -> Result<Vec<Option<Value>>> {
+        self.retry_request_batch_optional(method, params_list)
+    }
+
     // bitcoind JSONRPC API:
 
     fn getblockchaininfo(&self) -> Result<BlockchainInfo> {
@@ -459,6 +755,10 @@ impl Daemon {
         Ok(from_value(info).chain_err(|| "invalid network info")?)
     }
 
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
     pub fn get_subversion(&self) -> Result<String> {
         Ok(self.getnetworkinfo()?.subversion)
     }
@@ -467,6 +767,10 @@ impl Daemon {
         Ok(self.getnetworkinfo()?.relayfee)
     }
 
+    pub fn get_tip_height(&self) -> Result<usize> {
+        Ok(self.getblockchaininfo()?.blocks as usize)
+    }
+
     pub fn getbestblockhash(&self) -> Result<BlockHash> {
         parse_hash(&self.request("getbestblockhash", json!([]))?).chain_err(|| "invalid blockhash")
     }
@@ -493,6 +797,9 @@ impl Daemon {
     }
 
     pub fn getblock(&self, blockhash: &BlockHash) -> Result<Block> {
+        if let Some(block) = self.read_block(blockhash) {
+            return Ok(block);
+        }
         let block = block_from_value(
             self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false]))?,
         )?;
@@ -500,6 +807,16 @@ impl Daemon {
         Ok(block)
     }
 
+    // Blocks that are not found in blk*.dat files are fetched via JSONRPC.
+    fn read_block(&self, blockhash: &BlockHash) -> Option<Block> {
+        self.blk_files.as_ref()?.read_block(blockhash)
+    }
+
+    /// Reads a confirmed transaction from blk*.dat files (if enabled), without JSONRPC.
+    pub fn read_transaction(&self, txid: &Txid, blockhash: &BlockHash) -> Option<Transaction> {
+        self.blk_files.as_ref()?.read_transaction(txid, blockhash)
+    }
+
     fn load_blocktxids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
         self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ 1]))?
             .get("tx")
@@ -550,21 +867,55 @@ impl Daemon {
         Ok(result)
     }
 
+    /// Returns the mempool txids, and its sequence number (if supported by bitcoind).
+    pub fn getmempooltxids_with_sequence(&self) -> Result<(HashSet<Txid>, Option<u64>)> {
+        let reply = match self.request("getrawmempool", json!([false, /*mempool_sequence=*/ true]))
+        {
+            Ok(reply) => reply,
+            Err(Error(ErrorKind::Daemon(_, err), _)) => {
+                debug!("mempool_sequence is not supported: {}", err); // bitcoind < 0.21
+                return Ok((self.getmempooltxids()?, None));
+            }
+            Err(e) => return Err(e),
+        };
+        let sequence = reply
+            .get("mempool_sequence")
+            .and_then(Value::as_u64)
+            .chain_err(|| "missing mempool_sequence")?;
+        let mut txids = HashSet::new();
+        for value in reply
+            .get("txids")
+            .and_then(Value::as_array)
+            .chain_err(|| "missing txids")?
+        {
+            txids.insert(parse_hash(&value).chain_err(|| "invalid txid")?);
+        }
+        Ok((txids, Some(sequence)))
+    }
+
     pub fn getmempoolentry(&self, txid: &Txid) -> Result<MempoolEntry> {
-        let entry = self.request("getmempoolentry", json!([txid.to_hex()]))?;
-        let fee = (entry
-            .get("fee")
-            .chain_err(|| "missing fee")?
-            .as_f64()
-            .chain_err(|| "non-float fee")?
-            * 100_000_000f64) as u64;
-        let vsize = entry
-            .get("size")
-            .or_else(|| entry.get("vsize")) // (https://github.com/bitcoin/bitcoin/pull/15637)
-            .chain_err(|| "missing vsize")?
-            .as_u64()
-            .chain_err(|| "non-integer vsize")? as u32;
-        Ok(MempoolEntry::new(fee, vsize))
+        mempool_entry_from_value(&self.request("getmempoolentry", json!([txid.to_hex()]))?)
+    }
+
+    /// Fetches the entries in a single batch (`None` for transactions not in the mempool).
+    pub fn getmempoolentries(&self, txids: &[Txid]) -> Result<Vec<Option<MempoolEntry>>> {
+        let params_list: Vec<Value> = txids.iter().map(|txid| json!([txid.to_hex()])).collect();
+        self.requests_optional("getmempoolentry", &params_list)?
+            .into_iter()
+            .map(|entry| entry.as_ref().map(mempool_entry_from_value).transpose())
+            .collect()
+    }
+
+    /// Fetches the transactions in a single batch (`None` for missing transactions).
+    pub fn gettransactions(&self, txids: &[Txid]) -> Result<Vec<Option<Transaction>>> {
+        let params_list: Vec<Value> = txids
+            .iter()
+            .map(|txid| json!([txid.to_hex(), /*verbose=*/ false]))
+            .collect();
+        self.requests_optional("getrawtransaction", &params_list)?
+            .into_iter()
+            .map(|tx| tx.map(tx_from_value).transpose())
+            .collect()
     }
 
     pub fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
@@ -636,3 +987,185 @@ impl Daemon {
         Ok(new_headers)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    struct EmptyCookie;
+
+    impl CookieGetter for EmptyCookie {
+        fn get(&self) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+    }
+
+    fn timeouts() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            request: Some(Duration::from_secs(10)),
+        }
+    }
+
+    fn backend(endpoint: Endpoint, metrics: &Metrics) -> Backend {
+        let pool = ConnectionPool::new(endpoint, Arc::new(EmptyCookie), timeouts(), 1, metrics);
+        Backend::new(pool, metrics)
+    }
+
+    // Answers a stub bitcoind's health check requests, until the connection is closed.
+    fn serve(stream: TcpStream, network: Network, blocks: u32, pruned: bool) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        loop {
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return;
+                }
+                let line = line.trim_end().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if line.starts_with("content-length:") {
+                    length = line["content-length:".len()..].trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let result = match request["method"].as_str().unwrap() {
+                "getblockhash" => json!(genesis_block(network).block_hash().to_hex()),
+                "getblockchaininfo" => json!({
+                    "chain": "regtest",
+                    "blocks": blocks,
+                    "headers": blocks,
+                    "verificationprogress": 1.0,
+                    "bestblockhash": "",
+                    "pruned": pruned,
+                    "initialblockdownload": false,
+                }),
+                method => panic!("unexpected method {}", method),
+            };
+            let reply = json!({"result": result, "error": null, "id": request["id"]}).to_string();
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", reply.len());
+            writer.write_all(response.as_bytes()).unwrap();
+            writer.write_all(reply.as_bytes()).unwrap();
+        }
+    }
+
+    fn start_node(network: Network, blocks: u32, pruned: bool) -> Endpoint {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || serve(stream, network, blocks, pruned));
+            }
+        });
+        Endpoint::Tcp(addr)
+    }
+
+    fn unreachable_node() -> Endpoint {
+        let name = format!("electrs-missing-{}.sock", std::process::id());
+        Endpoint::Unix(std::env::temp_dir().join(name)) // never created
+    }
+
+    #[test]
+    fn test_connection_pool() {
+        // connections are accepted by the listener's backlog, and no request is sent
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let metrics = Metrics::new(([127, 0, 0, 1], 0).into());
+        let pool = ConnectionPool::new(
+            Endpoint::Tcp(listener.local_addr().unwrap()),
+            Arc::new(EmptyCookie),
+            timeouts(),
+            2,
+            &metrics,
+        );
+        let pool = Arc::new(pool);
+        assert_eq!(pool.connections.get(), 0); // opened on demand
+
+        let first = pool.get().unwrap();
+        assert_eq!(pool.connections.get(), 1);
+        let second = pool.get().unwrap(); // opens a new connection
+        assert_eq!(pool.connections.get(), 2);
+        assert_eq!(pool.busy.get(), 2);
+
+        // the pool is exhausted, so the next request waits for a connection to be released
+        let waiting = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                let conn = pool.get().unwrap();
+                pool.put(conn);
+            })
+        };
+        pool.put(first);
+        waiting.join().unwrap();
+        pool.put(second);
+        assert_eq!(pool.connections.get(), 2);
+        assert_eq!(pool.busy.get(), 0);
+        assert_eq!(pool.state.lock().unwrap().idle.len(), 2);
+    }
+
+    #[test]
+    fn test_failover() {
+        let network = Network::Regtest;
+        let metrics = Metrics::new(([127, 0, 0, 1], 0).into());
+        let backends = Backends::new(
+            vec![
+                backend(unreachable_node(), &metrics),
+                backend(start_node(Network::Testnet, 100, false), &metrics), // wrong chain
+                backend(start_node(network, 100, true), &metrics),           // pruned
+                backend(start_node(network, 90, false), &metrics),           // lagging behind
+                backend(start_node(network, 100, false), &metrics),
+                backend(start_node(network, 99, false), &metrics),
+            ],
+            network,
+            &metrics,
+        );
+        assert_eq!(backends.select(None).unwrap(), Some(4));
+        let healthy: Vec<i64> = backends.list.iter().map(|b| b.healthy.get()).collect();
+        assert_eq!(healthy, vec![0, 0, 0, 0, 1, 1]);
+        assert_eq!(backends.list[3].height.get(), 90);
+
+        backends.activate(4);
+        assert_eq!(backends.active_index.get(), 4);
+        assert!(backends.failover(4));
+        assert_eq!(backends.active(), 5);
+        assert!(backends.failover(4)); // already switched
+        assert_eq!(backends.active(), 5);
+    }
+
+    #[test]
+    fn test_unusable_backends() {
+        let network = Network::Regtest;
+        let select = |endpoints: Vec<Endpoint>| {
+            let metrics = Metrics::new(([127, 0, 0, 1], 0).into()); // registered by each case
+            let list = endpoints
+                .into_iter()
+                .map(|endpoint| backend(endpoint, &metrics))
+                .collect();
+            Backends::new(list, network, &metrics).select(None)
+        };
+        // an unreachable node may still become usable
+        assert_eq!(select(vec![unreachable_node()]).unwrap(), None);
+        assert_eq!(
+            select(vec![
+                start_node(Network::Testnet, 100, false),
+                unreachable_node()
+            ])
+            .unwrap(),
+            None
+        );
+        // but a pruned node (or one following another chain) won't
+        assert!(select(vec![start_node(network, 100, true)]).is_err());
+        assert!(select(vec![
+            start_node(network, 100, true),
+            start_node(Network::Testnet, 100, false)
+        ])
+        .is_err());
+    }
+}
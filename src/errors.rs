@@ -35,5 +35,10 @@ error_chain! {
             description("parse error")
             display("parse error")
         }
+
+        Syncing(height: usize, tip: usize) {
+            description("server is syncing")
+            display("server is syncing (height {} of {})", height, tip)
+        }
     }
 }
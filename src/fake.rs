@@ -14,9 +14,244 @@ impl ReadStore for FakeStore {
 
 impl WriteStore for FakeStore {
     fn write<I: IntoIterator<Item = Row>>(&self, _rows: I) {}
+    fn delete_and_write<K, I>(&self, _keys: K, _rows: I)
+    where
+        K: IntoIterator<Item = Bytes>,
+        I: IntoIterator<Item = Row>,
+    {
+    }
     fn flush(&self) {}
 }
 
+/// A fake bitcoind, serving the JSONRPC methods used for indexing (and querying) its
+/// (replaceable) best chain.
+#[cfg(test)]
+pub mod bitcoind {
+    use bitcoin::blockdata::block::Block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hash_types::{BlockHash, Txid};
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::network::constants::Network;
+    use serde_json::Value;
+    use std::collections::{HashMap, HashSet};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::cache::BlockTxIDsCache;
+    use crate::daemon::{CookieGetter, Daemon};
+    use crate::errors::*;
+    use crate::http::{Endpoint, Timeouts};
+    use crate::metrics::Metrics;
+    use crate::signal::Waiter;
+
+    #[derive(Default)]
+    struct Chain {
+        best: Vec<BlockHash>,
+        blocks: HashMap<BlockHash, Block>, // including the stale ones
+        mempool: HashSet<Txid>,
+        methods: Vec<String>, // of the received requests
+    }
+
+    impl Chain {
+        fn block(&self, hash: &Value) -> Option<&Block> {
+            let hash = BlockHash::from_hex(hash.as_str()?).ok()?;
+            self.blocks.get(&hash)
+        }
+
+        fn handle(&self, method: &str, params: &Value) -> Option<Value> {
+            let tip = self.best.len() - 1;
+            Some(match method {
+                "getnetworkinfo" => json!({
+                    "version": 21_00_00,
+                    "subversion": "/Satoshi:0.21.0/",
+                    "relayfee": 0.00001,
+                }),
+                "getblockchaininfo" => json!({
+                    "chain": "regtest",
+                    "blocks": tip,
+                    "headers": tip,
+                    "verificationprogress": 1.0,
+                    "bestblockhash": self.best[tip].to_hex(),
+                    "pruned": false,
+                    "initialblockdownload": false,
+                }),
+                "getbestblockhash" => json!(self.best[tip].to_hex()),
+                "getblockhash" => json!(self.best.get(params[0].as_u64()? as usize)?.to_hex()),
+                "getblockheader" => {
+                    let block = self.block(&params[0])?;
+                    if params[1] == json!(false) {
+                        json!(hex::encode(serialize(&block.header)))
+                    } else {
+                        let hash = block.block_hash();
+                        let height = self.best.iter().position(|h| *h == hash)?;
+                        json!({"hash": hash.to_hex(), "height": height})
+                    }
+                }
+                "getblock" => {
+                    let block = self.block(&params[0])?;
+                    if params[1] == json!(false) {
+                        json!(hex::encode(serialize(block)))
+                    } else {
+                        let txids: Vec<String> =
+                            block.txdata.iter().map(|tx| tx.txid().to_hex()).collect();
+                        json!({"hash": block.block_hash().to_hex(), "tx": txids})
+                    }
+                }
+                "getrawtransaction" => {
+                    let txid = Txid::from_hex(params[0].as_str()?).ok()?;
+                    let tx = self
+                        .block(&params[2])?
+                        .txdata
+                        .iter()
+                        .find(|tx| tx.txid() == txid)?;
+                    json!(hex::encode(serialize(tx)))
+                }
+                "getmempoolentry" => {
+                    let txid = Txid::from_hex(params[0].as_str()?).ok()?;
+                    if !self.mempool.contains(&txid) {
+                        return None;
+                    }
+                    json!({"fees": {"base": 0.00001}, "vsize": 100})
+                }
+                _ => return None,
+            })
+        }
+
+        fn reply(&mut self, request: &Value) -> Value {
+            let method = request["method"].as_str().unwrap_or("");
+            self.methods.push(method.to_owned());
+            match self.handle(method, &request["params"]) {
+                Some(result) => json!({"result": result, "error": null, "id": request["id"]}),
+                None => json!({
+                    "result": null,
+                    "error": {"code": -5, "message": format!("{} failed", method)},
+                    "id": request["id"],
+                }),
+            }
+        }
+    }
+
+    struct EmptyCookie;
+
+    impl CookieGetter for EmptyCookie {
+        fn get(&self) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+    }
+
+    pub struct FakeBitcoind {
+        chain: Arc<Mutex<Chain>>,
+        endpoint: Endpoint,
+    }
+
+    fn serve(stream: TcpStream, chain: Arc<Mutex<Chain>>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        loop {
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let line = line.trim_end().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if line.starts_with("content-length:") {
+                    length = line["content-length:".len()..].trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let reply = {
+                let mut chain = chain.lock().unwrap();
+                match request {
+                    Value::Array(requests) => {
+                        Value::Array(requests.iter().map(|r| chain.reply(r)).collect())
+                    }
+                    request => chain.reply(&request),
+                }
+            };
+            let reply = reply.to_string();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", reply.len());
+            writer.write_all(head.as_bytes()).unwrap();
+            writer.write_all(reply.as_bytes()).unwrap();
+        }
+    }
+
+    impl FakeBitcoind {
+        pub fn start(blocks: Vec<Block>) -> FakeBitcoind {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let endpoint = Endpoint::Tcp(listener.local_addr().unwrap());
+            let chain = Arc::new(Mutex::new(Chain::default()));
+            let bitcoind = FakeBitcoind {
+                chain: Arc::clone(&chain),
+                endpoint,
+            };
+            bitcoind.set_chain(blocks);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let chain = Arc::clone(&chain);
+                    thread::spawn(move || serve(stream.unwrap(), chain));
+                }
+            });
+            bitcoind
+        }
+
+        /// Replaces the best chain (e.g. for simulating a reorg).
+        pub fn set_chain(&self, blocks: Vec<Block>) {
+            let mut chain = self.chain.lock().unwrap();
+            chain.best = blocks.iter().map(Block::block_hash).collect();
+            for block in blocks {
+                chain.blocks.insert(block.block_hash(), block);
+            }
+        }
+
+        /// Replaces the mempool's txids (having a fixed fee and vsize).
+        pub fn set_mempool(&self, txids: &[Txid]) {
+            self.chain.lock().unwrap().mempool = txids.iter().cloned().collect();
+        }
+
+        /// Returns the number of requests received for `method`.
+        pub fn requests(&self, method: &str) -> usize {
+            let chain = self.chain.lock().unwrap();
+            chain.methods.iter().filter(|m| *m == method).count()
+        }
+
+        pub fn endpoint(&self) -> Endpoint {
+            self.endpoint.clone()
+        }
+
+        /// Returns a (regtest) `Daemon` connected to this node.
+        pub fn daemon(&self, signal: &Waiter, metrics: &Metrics) -> Daemon {
+            let timeouts = Timeouts {
+                connect: Some(Duration::from_secs(10)),
+                request: Some(Duration::from_secs(10)),
+            };
+            Daemon::new(
+                &PathBuf::new(),
+                &PathBuf::new(),
+                /*read_blk_files=*/ false,
+                vec![self.endpoint()],
+                Arc::new(EmptyCookie),
+                timeouts,
+                /*connections=*/ 1,
+                Network::Regtest,
+                signal.clone(),
+                Arc::new(BlockTxIDsCache::new(0, metrics)),
+                metrics,
+            )
+            .unwrap()
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
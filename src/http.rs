@@ -0,0 +1,553 @@
+//! A minimal HTTP/1.1 client, used for the bitcoind JSONRPC connection.
+//!
+//! Supports persistent (keep-alive) connections, which are transparently re-established
+//! if closed by the server, and both `Content-Length` and chunked response bodies.
+//! The server can be reached over TCP, HTTPS (using the 'tls' feature) or a Unix socket.
+
+use std::fmt;
+#[cfg(feature = "tls")]
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::errors::*;
+
+const MAX_LINE_SIZE: u64 = 64 * 1024; // for the status, header and chunk size lines
+const MAX_HEADERS: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub connect: Option<Duration>,
+    pub request: Option<Duration>, // for each socket read and write
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Https {
+        addr: SocketAddr,
+        domain: String,           // used for verifying the server's certificate
+        ca_file: Option<PathBuf>, // if set, only certificates issued by this CA are accepted
+    },
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    fn host(&self) -> String {
+        match self {
+            Endpoint::Tcp(addr) => addr.to_string(),
+            Endpoint::Https { addr, domain, .. } => format!("{}:{}", domain, addr.port()),
+            Endpoint::Unix(_) => "localhost".to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            Endpoint::Https { addr, domain, .. } => write!(f, "https://{} ({})", domain, addr),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    headers: Vec<(String, String)>, // names are lowercase
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).map_or(false, |value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    }
+}
+
+fn connection_error(msg: &str) -> ErrorKind {
+    ErrorKind::Connection(msg.to_owned())
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+/// Reads a CRLF (or LF) terminated line, without the line terminator.
+/// Returns `None` on EOF.
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = vec![];
+    reader
+        .take(MAX_LINE_SIZE)
+        .read_until(b'\n', &mut line)
+        .chain_err(|| connection_error("failed to read line"))?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        bail!(connection_error("unterminated or too long line"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(
+        String::from_utf8(line).chain_err(|| connection_error("non UTF-8 line"))?,
+    ))
+}
+
+fn parse_status_line(line: &str) -> Result<(bool, u16, String)> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => bail!(connection_error(&format!(
+            "invalid status line: {:?}",
+            line
+        ))),
+    };
+    let status = parts
+        .next()
+        .and_then(|code| code.parse::<u16>().ok())
+        .chain_err(|| connection_error(&format!("invalid status line: {:?}", line)))?;
+    let reason = parts.next().unwrap_or("").to_owned(); // may be empty
+    Ok((keep_alive, status, reason))
+}
+
+fn read_headers(reader: &mut impl BufRead) -> Result<Vec<(String, String)>> {
+    let mut headers = vec![];
+    loop {
+        let line = read_line(reader)?.chain_err(|| connection_error("truncated headers"))?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if headers.len() == MAX_HEADERS {
+            bail!(connection_error("too many headers"));
+        }
+        let sep = line
+            .find(':')
+            .chain_err(|| connection_error(&format!("invalid header: {:?}", line)))?;
+        let name = line[..sep].trim().to_ascii_lowercase();
+        let value = line[sep + 1..].trim().to_owned();
+        headers.push((name, value));
+    }
+}
+
+fn read_chunked_body(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = read_line(reader)?.chain_err(|| connection_error("truncated chunk"))?;
+        let size = line.split(';').next().unwrap().trim(); // ignore chunk extensions
+        let size = usize::from_str_radix(size, 16)
+            .chain_err(|| connection_error(&format!("invalid chunk size: {:?}", line)))?;
+        if size == 0 {
+            read_headers(reader)?; // skip trailers
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader
+            .read_exact(&mut body[start..])
+            .chain_err(|| connection_error("failed to read chunk"))?;
+        match read_line(reader)? {
+            Some(ref line) if line.is_empty() => (),
+            _ => bail!(connection_error("missing CRLF after chunk")),
+        }
+    }
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+fn tcp_connect(addr: SocketAddr, timeouts: &Timeouts) -> io::Result<TcpStream> {
+    let stream = match timeouts.connect {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+        None => TcpStream::connect(addr)?,
+    };
+    stream.set_read_timeout(timeouts.request)?;
+    stream.set_write_timeout(timeouts.request)?;
+    Ok(stream)
+}
+
+fn unix_connect(path: &Path, timeouts: &Timeouts) -> io::Result<UnixStream> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(timeouts.request)?;
+    stream.set_write_timeout(timeouts.request)?;
+    Ok(stream)
+}
+
+#[cfg(feature = "tls")]
+fn tls_connect(stream: TcpStream, domain: &str, ca_file: Option<&Path>) -> Result<Box<dyn Stream>> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca_file) = ca_file {
+        let pem = fs::read(ca_file).chain_err(|| format!("failed to read {:?}", ca_file))?;
+        let cert = native_tls::Certificate::from_pem(&pem)
+            .chain_err(|| format!("invalid CA certificate {:?}", ca_file))?;
+        builder
+            .add_root_certificate(cert)
+            .disable_built_in_roots(true);
+    }
+    let connector = builder
+        .build()
+        .chain_err(|| "failed to create TLS connector")?;
+    let stream = connector
+        .connect(domain, stream)
+        .map_err(|e| connection_error(&format!("TLS handshake with {} failed: {}", domain, e)))?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_connect(_: TcpStream, _: &str, _: Option<&Path>) -> Result<Box<dyn Stream>> {
+    bail!("HTTPS support requires the 'tls' feature")
+}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>, // requests are written via `get_mut()`
+}
+
+impl Connection {
+    fn open(endpoint: &Endpoint, timeouts: &Timeouts) -> Result<Connection> {
+        let failed =
+            |e: io::Error| connection_error(&format!("failed to connect to {}: {}", endpoint, e));
+        let stream: Box<dyn Stream> = match endpoint {
+            Endpoint::Tcp(addr) => Box::new(tcp_connect(*addr, timeouts).map_err(failed)?),
+            Endpoint::Https {
+                addr,
+                domain,
+                ca_file,
+            } => {
+                let stream = tcp_connect(*addr, timeouts).map_err(failed)?;
+                tls_connect(stream, domain, ca_file.as_deref())?
+            }
+            Endpoint::Unix(path) => Box::new(unix_connect(path, timeouts).map_err(failed)?),
+        };
+        Ok(Connection {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(request)?;
+        stream.flush()
+    }
+
+    /// Returns `None` if the connection was closed before the response has started.
+    fn read_response(&mut self) -> Result<Option<(Response, bool)>> {
+        match self.stream.fill_buf() {
+            Ok(buf) if buf.is_empty() => return Ok(None),
+            Ok(_) => (),
+            Err(e) => return Err(e).chain_err(|| connection_error("failed to read response")),
+        }
+        let line = read_line(&mut self.stream)?.chain_err(|| connection_error("no response"))?;
+        let (mut keep_alive, mut status, mut reason) = parse_status_line(&line)?;
+        let mut headers = read_headers(&mut self.stream)?;
+        while (100..200).contains(&status) {
+            // skip interim responses (e.g. "100 Continue")
+            let line = read_line(&mut self.stream)?
+                .chain_err(|| connection_error("missing final response"))?;
+            let (next_keep_alive, next_status, next_reason) = parse_status_line(&line)?;
+            keep_alive = next_keep_alive;
+            status = next_status;
+            reason = next_reason;
+            headers = read_headers(&mut self.stream)?;
+        }
+        let mut response = Response {
+            status,
+            reason,
+            headers,
+            body: vec![],
+        };
+        if response.has_token("Connection", "close") {
+            keep_alive = false;
+        } else if response.has_token("Connection", "keep-alive") {
+            keep_alive = true;
+        }
+        if status == 204 || status == 304 {
+            // no body
+        } else if response.has_token("Transfer-Encoding", "chunked") {
+            response.body = read_chunked_body(&mut self.stream)?;
+        } else if let Some(length) = response.header("Content-Length") {
+            let length: usize = length
+                .parse()
+                .chain_err(|| connection_error(&format!("invalid Content-Length: {}", length)))?;
+            let mut body = vec![0u8; length];
+            self.stream
+                .read_exact(&mut body)
+                .chain_err(|| connection_error("failed to read body"))?;
+            response.body = body;
+        } else {
+            // the body is delimited by closing the connection
+            self.stream
+                .read_to_end(&mut response.body)
+                .chain_err(|| connection_error("failed to read body"))?;
+            keep_alive = false;
+        }
+        Ok(Some((response, keep_alive)))
+    }
+}
+
+/// An HTTP/1.1 client, keeping a persistent connection to a single server.
+pub struct Client {
+    endpoint: Endpoint,
+    timeouts: Timeouts,
+    conn: Option<Connection>, // re-opened on the next request, if closed
+}
+
+impl Client {
+    pub fn connect(endpoint: Endpoint, timeouts: Timeouts) -> Result<Client> {
+        Ok(Client {
+            conn: Some(Connection::open(&endpoint, &timeouts)?),
+            endpoint,
+            timeouts,
+        })
+    }
+
+    pub fn post(&mut self, path: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<Response> {
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+            path,
+            self.endpoint.host(),
+            body.len()
+        );
+        for (name, value) in headers {
+            request += &format!("{}: {}\r\n", name, value);
+        }
+        request += "\r\n";
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+
+        loop {
+            let reused = self.conn.is_some();
+            let mut conn = match self.conn.take() {
+                Some(conn) => conn,
+                None => Connection::open(&self.endpoint, &self.timeouts)?,
+            };
+            // The request is re-sent only if it can't have been processed by the server,
+            // i.e. if it couldn't be written or if the server closed the connection without
+            // replying (other errors, e.g. a connection reset, are returned to the caller).
+            let result = match conn.send(&request) {
+                Ok(()) => conn.read_response(),
+                Err(ref e) if reused && !is_timeout(e) => Ok(None),
+                Err(e) => Err(e).chain_err(|| connection_error("failed to send request")),
+            };
+            match result? {
+                Some((response, keep_alive)) => {
+                    if keep_alive {
+                        self.conn = Some(conn);
+                    }
+                    return Ok(response);
+                }
+                // The server may close an idle persistent connection at any time
+                None if reused => debug!("reconnecting to {} (connection closed)", self.endpoint),
+                None => bail!(connection_error("connection closed without a response")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    type Responses = Vec<Option<&'static str>>;
+
+    // Stands in for an HTTP server, replying to each request using the given responses.
+    // A `None` response closes the connection (before reading the next request).
+    fn serve(stream: impl Read + Write, responses: Responses, requests: &mut Vec<String>) {
+        let mut stream = BufReader::new(stream);
+        for response in responses {
+            let response = match response {
+                Some(response) => response,
+                None => break,
+            };
+            let request_line = read_line(&mut stream).unwrap().unwrap();
+            assert_eq!(request_line, "POST / HTTP/1.1");
+            let headers = read_headers(&mut stream).unwrap();
+            let length: usize = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .map(|(_, value)| value.parse().unwrap())
+                .unwrap();
+            let mut body = vec![0u8; length];
+            stream.read_exact(&mut body).unwrap();
+            requests.push(String::from_utf8(body).unwrap());
+            stream.get_mut().write_all(response.as_bytes()).unwrap();
+        }
+    }
+
+    fn start_server(connections: Vec<Responses>) -> (Endpoint, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            for responses in connections {
+                let (stream, _) = listener.accept().unwrap();
+                serve(stream, responses, &mut requests);
+            }
+            requests
+        });
+        (endpoint, server)
+    }
+
+    fn timeouts() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            request: Some(Duration::from_secs(10)),
+        }
+    }
+
+    #[test]
+    fn test_responses() {
+        let (endpoint, server) = start_server(vec![vec![
+            Some("HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\n{\"a\":\n1}\n"),
+            Some(concat!(
+                "HTTP/1.1 200 Fine\r\nTransfer-Encoding: chunked\r\n\r\n",
+                "4;ext=1\r\n{\"b\"\r\n",
+                "4\r\n:\r\n2\r\n",
+                "1\r\n}\r\n",
+                "0\r\nTrailer: x\r\n\r\n"
+            )),
+            Some("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 500\r\nContent-Length: 2\r\n\r\n{}"),
+            Some("HTTP/1.0 200 OK\r\n\r\nuntil closed"),
+        ]]);
+        let mut client = Client::connect(endpoint, timeouts()).unwrap();
+
+        let response = client.post("/", &[], b"1").unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (200, "OK"));
+        assert_eq!(response.body, b"{\"a\":\n1}\n");
+        assert_eq!(response.header("content-length"), Some("9"));
+
+        let response = client.post("/", &[], b"2").unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (200, "Fine"));
+        assert_eq!(response.body, b"{\"b\":\r\n2}");
+
+        let response = client.post("/", &[], b"3").unwrap();
+        assert_eq!((response.status, response.reason.as_str()), (500, ""));
+        assert_eq!(response.body, b"{}");
+
+        let response = client.post("/", &[], b"4").unwrap();
+        assert_eq!(response.body, b"until closed");
+        assert!(client.conn.is_none());
+
+        assert_eq!(server.join().unwrap(), vec!["1", "2", "3", "4"]);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (endpoint, server) = start_server(vec![
+            vec![Some("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na"), None],
+            vec![Some(
+                "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 1\r\n\r\nb",
+            )],
+            vec![Some("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nc")],
+        ]);
+        let mut client = Client::connect(endpoint, timeouts()).unwrap();
+        let mut bodies = vec![];
+        for request in &["1", "2", "3"] {
+            let response = client.post("/", &[], request.as_bytes()).unwrap();
+            bodies.push(String::from_utf8(response.body).unwrap());
+        }
+        assert_eq!(bodies, vec!["a", "b", "c"]);
+        assert_eq!(server.join().unwrap(), vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_connection_reset() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = Endpoint::Tcp(listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            drop(stream); // resets the connection, since the request is not fully read
+            listener
+        });
+        let mut client = Client::connect(endpoint, timeouts()).unwrap();
+        match client.post("/", &[], b"1") {
+            Err(Error(ErrorKind::Connection(_), _)) => (),
+            result => panic!("unexpected result: {:?}", result.map(|r| r.status)),
+        }
+
+        // the request must not be re-sent
+        let listener = server.join().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let err = listener.accept().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("electrs-http-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            let (stream, _) = listener.accept().unwrap();
+            let responses = vec![Some("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na")];
+            serve(stream, responses, &mut requests);
+            requests
+        });
+        let mut client = Client::connect(Endpoint::Unix(path.clone()), timeouts()).unwrap();
+        let response = client.post("/", &[], b"1").unwrap();
+        assert_eq!(response.body, b"a");
+        assert_eq!(server.join().unwrap(), vec!["1"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_https() {
+        let fixtures = Path::new("src").join("tests").join("fixtures");
+        let cert = fs::read(fixtures.join("tls_cert.pem")).unwrap();
+        let key = fs::read(fixtures.join("tls_key.pem")).unwrap();
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key).unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut requests = vec![];
+            // the first client doesn't trust the (self-signed) certificate
+            let (stream, _) = listener.accept().unwrap();
+            assert!(acceptor.accept(stream).is_err());
+
+            let (stream, _) = listener.accept().unwrap();
+            let stream = acceptor.accept(stream).unwrap();
+            let responses = vec![Some("HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\na")];
+            serve(stream, responses, &mut requests);
+            requests
+        });
+        let endpoint = |ca_file| Endpoint::Https {
+            addr,
+            domain: "localhost".to_owned(),
+            ca_file,
+        };
+        assert!(Client::connect(endpoint(None), timeouts()).is_err());
+
+        let ca_file = Some(fixtures.join("tls_cert.pem"));
+        let mut client = Client::connect(endpoint(ca_file), timeouts()).unwrap();
+        let response = client.post("/", &[], b"1").unwrap();
+        assert_eq!(response.body, b"a");
+        assert_eq!(server.join().unwrap(), vec!["1"]);
+    }
+}
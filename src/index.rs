@@ -6,6 +6,7 @@ use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
 use crate::daemon::Daemon;
 use crate::errors::*;
@@ -53,6 +54,11 @@ impl TxInRow {
         .unwrap()
     }
 
+    /// Matches the inputs spending any output of `txid`.
+    pub fn filter_prefix(txid: &Txid) -> Bytes {
+        [b"I", &txid[..HASH_PREFIX_LEN]].concat()
+    }
+
     pub fn to_row(&self) -> Row {
         Row {
             key: bincode::serialize(&self).unwrap(),
@@ -60,8 +66,20 @@ impl TxInRow {
         }
     }
 
-    pub fn from_row(row: &Row) -> TxInRow {
-        bincode::deserialize(&row.key).expect("failed to parse TxInRow")
+    /// The extended row also stores the funding output index (which is truncated in the key).
+    pub fn to_extended_row(&self, funding_vout: u32) -> Row {
+        Row {
+            key: bincode::serialize(&self).unwrap(),
+            value: bincode::serialize(&funding_vout).unwrap(),
+        }
+    }
+
+    pub fn funding_vout(row: &Row) -> Result<u32> {
+        bincode::deserialize(&row.value).chain_err(|| "failed to parse funding vout")
+    }
+
+    pub fn from_row(row: &Row) -> Result<TxInRow> {
+        bincode::deserialize(&row.key).chain_err(|| "failed to parse TxInRow")
     }
 }
 
@@ -103,8 +121,68 @@ impl TxOutRow {
         }
     }
 
-    pub fn from_row(row: &Row) -> TxOutRow {
-        bincode::deserialize(&row.key).expect("failed to parse TxOutRow")
+    pub fn from_row(row: &Row) -> Result<TxOutRow> {
+        bincode::deserialize(&row.key).chain_err(|| "failed to parse TxOutRow")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FundingKey {
+    key: TxOutKey,
+    pub txid_prefix: HashPrefix,
+    pub output_index: u32,
+}
+
+/// Extended `O` row, which can be scanned using `TxOutRow::filter()`.
+/// The full script hash is stored too, since different scripts may share the key's prefix.
+pub struct FundingRow {
+    pub key: FundingKey,
+    pub value: u64,                    // amount (in satoshis)
+    pub script_hash: Option<FullHash>, // missing from rows written by older versions
+}
+
+impl FundingRow {
+    pub fn new(txid: &Txid, output_index: usize, output: &TxOut) -> FundingRow {
+        let script_hash = compute_script_hash(&output.script_pubkey[..]);
+        FundingRow {
+            key: FundingKey {
+                key: TxOutKey {
+                    code: b'O',
+                    script_hash_prefix: hash_prefix(&script_hash),
+                },
+                txid_prefix: hash_prefix(&txid[..]),
+                output_index: output_index as u32,
+            },
+            value: output.value,
+            script_hash: Some(script_hash),
+        }
+    }
+
+    pub fn to_row(&self) -> Row {
+        let value = match self.script_hash {
+            Some(script_hash) => bincode::serialize(&(self.value, script_hash)).unwrap(),
+            None => bincode::serialize(&self.value).unwrap(),
+        };
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value,
+        }
+    }
+
+    pub fn from_row(row: &Row) -> Result<FundingRow> {
+        let (value, script_hash) = if row.value.len() == 8 {
+            let value = bincode::deserialize(&row.value).chain_err(|| "failed to parse amount")?;
+            (value, None)
+        } else {
+            let (value, script_hash) = bincode::deserialize(&row.value)
+                .chain_err(|| "failed to parse amount and script hash")?;
+            (value, Some(script_hash))
+        };
+        Ok(FundingRow {
+            key: bincode::deserialize(&row.key).chain_err(|| "failed to parse FundingKey")?,
+            value,
+            script_hash,
+        })
     }
 }
 
@@ -116,17 +194,19 @@ pub struct TxKey {
 
 pub struct TxRow {
     pub key: TxKey,
-    pub height: u32, // value
+    pub height: u32,           // value
+    pub position: Option<u32>, // within the block (missing for rows written by older versions)
 }
 
 impl TxRow {
-    pub fn new(txid: &Txid, height: u32) -> TxRow {
+    pub fn new(txid: &Txid, height: u32, position: Option<u32>) -> TxRow {
         TxRow {
             key: TxKey {
                 code: b'T',
                 txid: full_hash(&txid[..]),
             },
             height,
+            position,
         }
     }
 
@@ -139,17 +219,30 @@ impl TxRow {
     }
 
     pub fn to_row(&self) -> Row {
+        let value = match self.position {
+            Some(position) => bincode::serialize(&(self.height, position)).unwrap(),
+            None => bincode::serialize(&self.height).unwrap(),
+        };
         Row {
             key: bincode::serialize(&self.key).unwrap(),
-            value: bincode::serialize(&self.height).unwrap(),
+            value,
         }
     }
 
-    pub fn from_row(row: &Row) -> TxRow {
-        TxRow {
-            key: bincode::deserialize(&row.key).expect("failed to parse TxKey"),
-            height: bincode::deserialize(&row.value).expect("failed to parse height"),
-        }
+    pub fn from_row(row: &Row) -> Result<TxRow> {
+        let (height, position) = if row.value.len() == 4 {
+            let height = bincode::deserialize(&row.value).chain_err(|| "failed to parse height")?;
+            (height, None)
+        } else {
+            let (height, position) = bincode::deserialize(&row.value)
+                .chain_err(|| "failed to parse height and position")?;
+            (height, Some(position))
+        };
+        Ok(TxRow {
+            key: bincode::deserialize(&row.key).chain_err(|| "failed to parse TxKey")?,
+            height,
+            position,
+        })
     }
 }
 
@@ -165,9 +258,22 @@ pub fn compute_script_hash(data: &[u8]) -> FullHash {
     sha2.finalize().into()
 }
 
+/// Layout of the funding (`O`) and spending (`I`) rows (stored in the DB metadata).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RowFormat {
+    /// Only transaction ID prefixes are stored, so queries fetch the transactions from bitcoind.
+    Compact,
+    /// Output indices and amounts are also stored, so queries can be answered from the DB.
+    Extended,
+}
+
+/// Returns the rows of `txn`, confirmed at `height` and `position` within its block (both are
+/// ignored for mempool txns, using height 0 and no position).
 pub fn index_transaction<'a>(
     txn: &'a Transaction,
     height: usize,
+    position: Option<usize>,
+    format: RowFormat,
 ) -> impl 'a + Iterator<Item = Row> {
     let null_hash = Txid::default();
     let txid = txn.txid();
@@ -176,21 +282,32 @@ pub fn index_transaction<'a>(
         if input.previous_output.txid == null_hash {
             None
         } else {
-            Some(TxInRow::new(&txid, &input).to_row())
+            let row = TxInRow::new(&txid, &input);
+            Some(match format {
+                RowFormat::Compact => row.to_row(),
+                RowFormat::Extended => row.to_extended_row(input.previous_output.vout),
+            })
         }
     });
     let outputs = txn
         .output
         .iter()
-        .map(move |output| TxOutRow::new(&txid, &output).to_row());
+        .enumerate()
+        .map(move |(index, output)| match format {
+            RowFormat::Compact => TxOutRow::new(&txid, &output).to_row(),
+            RowFormat::Extended => FundingRow::new(&txid, index, &output).to_row(),
+        });
 
-    // Persist transaction ID and confirmed height
-    inputs
-        .chain(outputs)
-        .chain(std::iter::once(TxRow::new(&txid, height as u32).to_row()))
+    // Persist transaction ID, confirmed height and position
+    let row = TxRow::new(&txid, height as u32, position.map(|p| p as u32));
+    inputs.chain(outputs).chain(std::iter::once(row.to_row()))
 }
 
-pub fn index_block<'a>(block: &'a Block, height: usize) -> impl 'a + Iterator<Item = Row> {
+pub fn index_block<'a>(
+    block: &'a Block,
+    height: usize,
+    format: RowFormat,
+) -> impl 'a + Iterator<Item = Row> {
     let blockhash = block.block_hash();
     // Persist block hash and header
     let row = Row {
@@ -204,10 +321,25 @@ pub fn index_block<'a>(block: &'a Block, height: usize) -> impl 'a + Iterator<It
     block
         .txdata
         .iter()
-        .flat_map(move |txn| index_transaction(&txn, height))
+        .enumerate()
+        .flat_map(move |(position, txn)| index_transaction(&txn, height, Some(position), format))
         .chain(std::iter::once(row))
 }
 
+/// Deletes the rows of `stale_blocks` (disconnected by a reorg) and marks `fork_blockhash`
+/// as the last indexed block, using a single atomic write.
+pub fn undo_blocks(
+    store: &impl WriteStore,
+    stale_blocks: &[(Block, usize)],
+    fork_blockhash: &BlockHash,
+    format: RowFormat,
+) {
+    let keys = stale_blocks
+        .iter()
+        .flat_map(|(block, height)| index_block(block, *height, format).map(|row| row.key));
+    store.delete_and_write(keys, std::iter::once(last_indexed_block(fork_blockhash)));
+}
+
 pub fn last_indexed_block(blockhash: &BlockHash) -> Row {
     // Store last indexed block (i.e. all previous blocks were indexed)
     Row {
@@ -273,6 +405,7 @@ struct Stats {
     blocks: Counter,
     txns: Counter,
     vsize: Counter,
+    undone: Counter,
     height: Gauge,
     duration: HistogramVec,
 }
@@ -292,6 +425,10 @@ impl Stats {
                 "electrs_index_vsize",
                 "# of indexed vbytes",
             )),
+            undone: metrics.counter(MetricOpts::new(
+                "electrs_index_undone_blocks",
+                "# of undone (reorged) blocks",
+            )),
             height: metrics.gauge(MetricOpts::new(
                 "electrs_index_height",
                 "Last indexed block's height",
@@ -327,6 +464,7 @@ pub struct Index {
     daemon: Daemon,
     stats: Stats,
     batch_size: usize,
+    format: RowFormat,
 }
 
 impl Index {
@@ -335,6 +473,7 @@ impl Index {
         daemon: &Daemon,
         metrics: &Metrics,
         batch_size: usize,
+        format: RowFormat,
     ) -> Result<Index> {
         let stats = Stats::new(metrics);
         let headers = read_indexed_headers(store);
@@ -344,9 +483,14 @@ impl Index {
             daemon: daemon.reconnect()?,
             stats,
             batch_size,
+            format,
         })
     }
 
+    pub fn row_format(&self) -> RowFormat {
+        self.format
+    }
+
     pub fn reload(&self, store: &dyn ReadStore) {
         let mut headers = self.headers.write().unwrap();
         *headers = read_indexed_headers(store);
@@ -368,10 +512,24 @@ impl Index {
     pub fn update(&self, store: &impl WriteStore, waiter: &Waiter) -> Result<BlockHash> {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
-        let new_headers: Vec<HeaderEntry> = {
+        let (new_headers, stale_headers) = {
             let indexed_headers = self.headers.read().unwrap();
-            indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?)
+            let new_headers =
+                indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?);
+            // Indexed blocks at (or above) the fork height are not part of the best chain
+            let fork_height = match new_headers.first() {
+                Some(entry) => entry.height(),
+                None => indexed_headers
+                    .header_by_blockhash(&tip)
+                    .map_or(indexed_headers.len(), |entry| entry.height() + 1),
+            };
+            let stale_headers: Vec<HeaderEntry> =
+                indexed_headers.iter().skip(fork_height).cloned().collect();
+            (new_headers, stale_headers)
         };
+        if !stale_headers.is_empty() {
+            self.undo(store, &daemon, &stale_headers)?;
+        }
         if let Some(latest_header) = new_headers.last() {
             info!("{:?} ({} left to index)", latest_header, new_headers.len());
         };
@@ -416,7 +574,8 @@ impl Index {
                     .unwrap_or_else(|| panic!("missing header for block {}", blockhash));
 
                 self.stats.update(block, height); // TODO: update stats after the block is indexed
-                index_block(block, height).chain(std::iter::once(last_indexed_block(&blockhash)))
+                index_block(block, height, self.format)
+                    .chain(std::iter::once(last_indexed_block(&blockhash)))
             });
 
             let timer = self.stats.start_timer("index+write");
@@ -434,4 +593,329 @@ impl Index {
             .update_height(self.headers.read().unwrap().len() - 1);
         Ok(tip)
     }
+
+    fn undo(
+        &self,
+        store: &impl WriteStore,
+        daemon: &Daemon,
+        stale_headers: &[HeaderEntry],
+    ) -> Result<()> {
+        let fork_blockhash = stale_headers[0].header().prev_blockhash;
+        warn!(
+            "undoing {} reorged blocks (fork at {})",
+            stale_headers.len(),
+            fork_blockhash
+        );
+        let timer = self.stats.start_timer("undo");
+        let mut stale_blocks = vec![];
+        for entry in stale_headers.iter().rev() {
+            let block = daemon
+                .getblock(entry.hash())
+                .chain_err(|| format!("failed to get stale block {}", entry.hash()))?;
+            stale_blocks.push((block, entry.height()));
+        }
+        undo_blocks(store, &stale_blocks, &fork_blockhash, self.format);
+        timer.observe_duration();
+        self.stats.undone.inc_by(stale_blocks.len() as i64);
+        // Drop the stale headers, so they won't be used by queries until the new blocks are indexed
+        self.headers.write().unwrap().apply(vec![], fork_blockhash);
+        Ok(())
+    }
+}
+
+/// Tracks the initial sync, so RPC clients (and monitoring) can tell "syncing" from "down".
+pub struct SyncProgress {
+    height: Gauge, // shared with the index stats
+    tip: Gauge,
+    tip_header: RwLock<Option<(HeaderEntry, Instant)>>, // bitcoind's best header, when fetched
+    syncing: Gauge,
+}
+
+impl SyncProgress {
+    pub fn new(metrics: &Metrics, index: &Index) -> SyncProgress {
+        let progress = SyncProgress {
+            height: index.stats.height.clone(),
+            tip: metrics.gauge(MetricOpts::new(
+                "electrs_sync_tip_height",
+                "bitcoind's best block height (during initial sync)",
+            )),
+            tip_header: RwLock::new(None),
+            syncing: metrics.gauge(MetricOpts::new(
+                "electrs_syncing",
+                "Whether the initial sync is in progress",
+            )),
+        };
+        progress.syncing.set(1);
+        progress
+    }
+
+    pub fn set_height(&self, height: usize) {
+        self.height.set(height as i64);
+    }
+
+    pub fn set_tip(&self, tip: usize) {
+        self.tip.set(tip as i64);
+    }
+
+    pub fn set_tip_header(&self, header: HeaderEntry) {
+        self.set_tip(header.height());
+        *self.tip_header.write().unwrap() = Some((header, Instant::now()));
+    }
+
+    /// Returns the cached best header, unless it was fetched more than `max_age` ago.
+    pub fn tip_header(&self, max_age: Duration) -> Option<HeaderEntry> {
+        match &*self.tip_header.read().unwrap() {
+            Some((header, fetched)) if fetched.elapsed() <= max_age => Some(header.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the (indexed height, tip height) pair.
+    pub fn get(&self) -> (usize, usize) {
+        (self.height.get().max(0) as usize, self.tip.get() as usize)
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.syncing.get() != 0
+    }
+
+    pub fn finish(&self) {
+        self.syncing.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::bitcoind::FakeBitcoind;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::OutPoint;
+    use bitcoin::hash_types::TxMerkleNode;
+    use bitcoin::network::constants::Network;
+    use std::collections::BTreeMap;
+
+    struct MemStore {
+        rows: RwLock<BTreeMap<Bytes, Bytes>>,
+    }
+
+    impl MemStore {
+        fn new() -> MemStore {
+            MemStore {
+                rows: RwLock::new(BTreeMap::new()),
+            }
+        }
+    }
+
+    impl ReadStore for MemStore {
+        fn get(&self, key: &[u8]) -> Option<Bytes> {
+            self.rows.read().unwrap().get(key).cloned()
+        }
+        fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+            let rows = self.rows.read().unwrap();
+            rows.range(prefix.to_vec()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| Row {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect()
+        }
+    }
+
+    impl WriteStore for MemStore {
+        fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
+            self.delete_and_write(Vec::<Bytes>::new(), rows)
+        }
+        fn delete_and_write<K, I>(&self, keys: K, rows: I)
+        where
+            K: IntoIterator<Item = Bytes>,
+            I: IntoIterator<Item = Row>,
+        {
+            let mut map = self.rows.write().unwrap();
+            for key in keys {
+                map.remove(&key);
+            }
+            for row in rows {
+                map.insert(row.key, row.value);
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    fn coinbase(height: u8) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(vec![height]),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        }
+    }
+
+    fn spend(prev: &Transaction) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 40,
+                script_pubkey: Script::from(vec![0x52]),
+            }],
+        }
+    }
+
+    fn new_block(prev_blockhash: BlockHash, nonce: u32, txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::default(),
+                time: 0,
+                bits: 0,
+                nonce,
+            },
+            txdata,
+        }
+    }
+
+    fn index_chain(store: &MemStore, blocks: &[Block], start_height: usize) {
+        for (height, block) in (start_height..).zip(blocks) {
+            let rows = index_block(block, height, RowFormat::Compact)
+                .chain(std::iter::once(last_indexed_block(&block.block_hash())));
+            store.write(rows);
+        }
+    }
+
+    #[test]
+    fn test_undo_reorged_blocks() {
+        let block0 = new_block(BlockHash::default(), 0, vec![coinbase(0)]);
+        let block1 = new_block(block0.block_hash(), 1, vec![coinbase(1)]);
+        let spending = spend(&block0.txdata[0]);
+        let block2 = new_block(block1.block_hash(), 2, vec![coinbase(2), spending.clone()]);
+
+        let store = MemStore::new();
+        index_chain(&store, &[block0.clone(), block1.clone(), block2.clone()], 0);
+        assert_eq!(read_indexed_headers(&store).len(), 3);
+
+        // Disconnect blocks 1 and 2 (in reverse order, as done by `Index::undo`)
+        let stale_blocks = [(block2, 2), (block1, 1)];
+        undo_blocks(
+            &store,
+            &stale_blocks,
+            &block0.block_hash(),
+            RowFormat::Compact,
+        );
+        let expected = MemStore::new();
+        index_chain(&expected, &[block0.clone()], 0);
+        assert_eq!(*store.rows.read().unwrap(), *expected.rows.read().unwrap());
+
+        let headers = read_indexed_headers(&store);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers.tip(), block0.block_hash());
+
+        // Connect a competing block, confirming the spending transaction at a lower height
+        let other1 = new_block(block0.block_hash(), 3, vec![coinbase(1), spending.clone()]);
+        index_chain(&store, &[other1.clone()], 1);
+        let key = TxRow::filter_full(&spending.txid());
+        let value = store.get(&key).expect("missing spending tx");
+        let tx_row = TxRow::from_row(&Row { key, value }).unwrap();
+        assert_eq!((tx_row.height, tx_row.position), (1, Some(1)));
+        assert_eq!(read_indexed_headers(&store).tip(), other1.block_hash());
+        assert_eq!(read_indexed_blockhashes(&store).len(), 2);
+    }
+
+    #[test]
+    fn test_update_reorg() {
+        let network = Network::Regtest;
+        let block0 = genesis_block(network);
+        let block1 = new_block(block0.block_hash(), 1, vec![coinbase(1)]);
+        let spending = spend(&block1.txdata[0]);
+        let block2 = new_block(block1.block_hash(), 2, vec![coinbase(2), spending.clone()]);
+        let bitcoind = FakeBitcoind::start(vec![block0.clone(), block1.clone(), block2.clone()]);
+
+        let metrics = Metrics::new(([127, 0, 0, 1], 0).into());
+        let signal = Waiter::start();
+        let daemon = bitcoind.daemon(&signal, &metrics);
+        let store = MemStore::new();
+        let index = Index::load(&store, &daemon, &metrics, 10, RowFormat::Compact).unwrap();
+        assert_eq!(index.update(&store, &signal).unwrap(), block2.block_hash());
+        let key = TxRow::filter_full(&spending.txid());
+        assert!(store.get(&key).is_some());
+
+        // Replace the tip, orphaning the block that confirmed the spending transaction
+        let other2 = new_block(block1.block_hash(), 3, vec![coinbase(3)]);
+        bitcoind.set_chain(vec![block0.clone(), block1.clone(), other2.clone()]);
+        assert_eq!(index.update(&store, &signal).unwrap(), other2.block_hash());
+        assert_eq!(index.best_header().unwrap().hash(), &other2.block_hash());
+
+        // The orphaned block's input, output and transaction rows are removed
+        assert!(store.get(&key).is_none());
+        let expected = MemStore::new();
+        index_chain(&expected, &[block0, block1, other2], 0);
+        assert_eq!(*store.rows.read().unwrap(), *expected.rows.read().unwrap());
+    }
+
+    #[test]
+    fn test_extended_rows() {
+        let funding = coinbase(0);
+        let spending = spend(&funding);
+        let rows: Vec<Row> =
+            index_transaction(&spending, 1, Some(1), RowFormat::Extended).collect();
+        assert_eq!(rows.len(), 3); // spending input, funding output and transaction rows
+
+        let input = TxInRow::from_row(&rows[0]).unwrap();
+        assert_eq!(input.txid_prefix, hash_prefix(&spending.txid()[..]));
+        assert_eq!(TxInRow::funding_vout(&rows[0]).unwrap(), 0);
+        let filter = TxInRow::filter(&funding.txid(), 0);
+        assert!(rows[0].key.starts_with(&filter));
+
+        let output = FundingRow::from_row(&rows[1]).unwrap();
+        assert_eq!(output.key.txid_prefix, hash_prefix(&spending.txid()[..]));
+        assert_eq!(output.key.output_index, 0);
+        assert_eq!(output.value, 40);
+        let script_hash = compute_script_hash(&spending.output[0].script_pubkey[..]);
+        assert_eq!(output.script_hash, Some(script_hash));
+        assert!(rows[1].key.starts_with(&TxOutRow::filter(&script_hash)));
+
+        // Rows written by older versions don't store the script hash
+        let legacy = FundingRow {
+            script_hash: None,
+            ..output
+        }
+        .to_row();
+        assert_eq!(legacy.value.len(), 8);
+        let legacy = FundingRow::from_row(&legacy).unwrap();
+        assert_eq!(legacy.value, 40);
+        assert_eq!(legacy.script_hash, None);
+
+        let tx_row = TxRow::from_row(&rows[2]).unwrap();
+        assert_eq!((tx_row.height, tx_row.position), (1, Some(1)));
+        let legacy = TxRow::new(&spending.txid(), 1, None).to_row();
+        assert_eq!(legacy.value.len(), 4);
+        assert_eq!(TxRow::from_row(&legacy).unwrap().position, None);
+
+        let invalid = Row {
+            key: rows[1].key.clone(),
+            value: vec![1, 2, 3],
+        };
+        assert!(FundingRow::from_row(&invalid).is_err());
+        assert!(TxRow::from_row(&Row {
+            key: b"T".to_vec(),
+            value: vec![],
+        })
+        .is_err());
+    }
 }
@@ -12,17 +12,25 @@ extern crate serde_json;
 extern crate configure_me;
 
 pub mod app;
+pub mod blkfiles;
 pub mod bulk;
 pub mod cache;
 pub mod config;
 pub mod daemon;
 pub mod errors;
 pub mod fake;
+pub mod http;
 pub mod index;
 pub mod mempool;
 pub mod metrics;
+pub mod notify;
+pub mod peers;
 pub mod query;
 pub mod rpc;
 pub mod signal;
 pub mod store;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod util;
+pub mod websocket;
+pub mod zmq;
@@ -1,13 +1,16 @@
 use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::Txid;
 use std::collections::{BTreeMap, HashMap, HashSet};
-use std::iter::FromIterator;
+use std::fs;
+use std::io::ErrorKind::NotFound;
 use std::ops::Bound;
+use std::path::Path;
 use std::sync::Mutex;
 
 use crate::daemon::{Daemon, MempoolEntry};
 use crate::errors::*;
-use crate::index::index_transaction;
+use crate::index::{index_transaction, RowFormat};
 use crate::metrics::{
     Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
 };
@@ -15,6 +18,7 @@ use crate::store::{ReadStore, Row};
 use crate::util::Bytes;
 
 const VSIZE_BIN_WIDTH: u32 = 100_000; // in vbytes
+const FETCH_BATCH_SIZE: usize = 1000; // transactions per JSONRPC batch
 
 struct MempoolStore {
     map: BTreeMap<Bytes, Vec<Bytes>>,
@@ -28,7 +32,8 @@ impl MempoolStore {
     }
 
     fn add(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, 0);
+        // mempool queries load the transactions anyway, so there is no need for extended rows
+        let rows = index_transaction(tx, 0, None, RowFormat::Compact);
         for row in rows {
             let (key, value) = row.into_pair();
             self.map.entry(key).or_insert_with(Vec::new).push(value);
@@ -36,7 +41,7 @@ impl MempoolStore {
     }
 
     fn remove(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, 0);
+        let rows = index_transaction(tx, 0, None, RowFormat::Compact);
         for row in rows {
             let (key, value) = row.into_pair();
             let no_values_left = {
@@ -88,6 +93,16 @@ impl ReadStore for MempoolStore {
     }
 }
 
+const SNAPSHOT_VERSION: u32 = 1;
+
+// A mempool transaction, as persisted across restarts
+#[derive(Serialize, Deserialize)]
+struct SavedItem {
+    tx: Vec<u8>, // consensus-serialized
+    fee: u64,
+    vsize: u32,
+}
+
 struct Item {
     tx: Transaction,     // stored for faster retrieval and index removal
     entry: MempoolEntry, // caches mempool fee rates
@@ -137,10 +152,68 @@ impl Stats {
     }
 }
 
+/// Mempool changes, fetched from bitcoind without locking the tracker.
+pub struct Update {
+    sequence: Option<u64>,
+    added: Vec<(Txid, Transaction, MempoolEntry)>,
+    removed: Vec<Txid>,
+}
+
+/// The tracker's state needed for fetching the next update (see `Tracker::update_request()`).
+pub struct UpdateRequest {
+    txids: HashSet<Txid>,
+    sequence: Option<u64>,
+    update: HistogramVec,
+}
+
+impl UpdateRequest {
+    fn start_timer(&self, step: &str) -> HistogramTimer {
+        self.update.with_label_values(&[step]).start_timer()
+    }
+
+    /// Returns `None` if the mempool didn't change (according to bitcoind's `mempool_sequence`).
+    pub fn fetch(self, daemon: &Daemon) -> Result<Option<Update>> {
+        let timer = self.start_timer("fetch");
+        let (new_txids, sequence) = daemon
+            .getmempooltxids_with_sequence()
+            .chain_err(|| "failed to update mempool from daemon")?;
+        timer.observe_duration();
+        if sequence.is_some() && sequence == self.sequence {
+            return Ok(None);
+        }
+
+        let timer = self.start_timer("fetch_txns");
+        let txids: Vec<Txid> = new_txids.difference(&self.txids).cloned().collect();
+        let mut added = Vec::with_capacity(txids.len());
+        for chunk in txids.chunks(FETCH_BATCH_SIZE) {
+            let entries = daemon.getmempoolentries(chunk)?;
+            let txns = daemon.gettransactions(chunk)?;
+            for ((txid, entry), tx) in chunk.iter().zip(entries).zip(txns) {
+                match (entry, tx) {
+                    (Some(entry), Some(tx)) => {
+                        assert_eq!(tx.txid(), *txid);
+                        added.push((*txid, tx, entry));
+                    }
+                    _ => debug!("failed to fetch mempool tx {}", txid), // e.g. new block or RBF
+                }
+            }
+        }
+        timer.observe_duration();
+
+        let removed = self.txids.difference(&new_txids).cloned().collect();
+        Ok(Some(Update {
+            sequence,
+            added,
+            removed,
+        }))
+    }
+}
+
 pub struct Tracker {
     items: HashMap<Txid, Item>,
     index: MempoolStore,
     histogram: Vec<(f32, u32)>,
+    sequence: Option<u64>, // bitcoind's `mempool_sequence` of the last update
     stats: Stats,
 }
 
@@ -150,6 +223,7 @@ impl Tracker {
             items: HashMap::new(),
             index: MempoolStore::new(),
             histogram: vec![],
+            sequence: None,
             stats: Stats {
                 count: metrics.gauge(MetricOpts::new(
                     "electrs_mempool_count",
@@ -174,10 +248,25 @@ impl Tracker {
         }
     }
 
+    pub fn count(&self) -> usize {
+        self.items.len()
+    }
+
     pub fn has_txn(&self, txid: &Txid) -> bool {
         self.items.contains_key(txid)
     }
 
+    /// Returns whether the transaction spends any output of another mempool transaction
+    /// (reported with height -1 by the Electrum protocol).
+    pub fn has_unconfirmed_inputs(&self, txid: &Txid) -> bool {
+        self.items.get(txid).map_or(false, |item| {
+            item.tx
+                .input
+                .iter()
+                .any(|txin| self.items.contains_key(&txin.previous_output.txid))
+        })
+    }
+
     pub fn get_fee(&self, txid: &Txid) -> Option<u64> {
         self.items.get(txid).map(|stats| stats.entry.fee())
     }
@@ -193,41 +282,29 @@ impl Tracker {
         &self.index
     }
 
-    pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
-        let timer = self.stats.start_timer("fetch");
-        let new_txids = daemon
-            .getmempooltxids()
-            .chain_err(|| "failed to update mempool from daemon")?;
-        let old_txids = HashSet::from_iter(self.items.keys().cloned());
-        timer.observe_duration();
+    pub fn update_request(&self) -> UpdateRequest {
+        UpdateRequest {
+            txids: self.items.keys().cloned().collect(),
+            sequence: self.sequence,
+            update: self.stats.update.clone(),
+        }
+    }
 
+    /// Applies the fetched changes (transactions notified meanwhile are kept).
+    pub fn apply(&mut self, update: Update) {
         let timer = self.stats.start_timer("add");
-        let txids_iter = new_txids.difference(&old_txids);
-        let entries = txids_iter.filter_map(|txid| {
-            match daemon.getmempoolentry(txid) {
-                Ok(entry) => Some((txid, entry)),
-                Err(err) => {
-                    debug!("no mempool entry {}: {}", txid, err); // e.g. new block or RBF
-                    None // ignore this transaction for now
-                }
-            }
-        });
-        for (txid, entry) in entries {
-            match daemon.gettransaction(txid, None) {
-                Ok(tx) => {
-                    assert_eq!(tx.txid(), *txid);
-                    self.add(txid, tx, entry);
-                }
-                Err(err) => {
-                    debug!("failed to get transaction {}: {}", txid, err); // e.g. new block or RBF
-                }
+        for (txid, tx, entry) in update.added {
+            if !self.has_txn(&txid) {
+                self.add(&txid, tx, entry);
             }
         }
         timer.observe_duration();
 
         let timer = self.stats.start_timer("remove");
-        for txid in old_txids.difference(&new_txids) {
-            self.remove(txid);
+        for txid in &update.removed {
+            if self.has_txn(txid) {
+                self.remove(txid); // may be already removed by a concurrent update
+            }
         }
         timer.observe_duration();
 
@@ -235,10 +312,72 @@ impl Tracker {
         self.update_fee_histogram();
         timer.observe_duration();
 
+        self.sequence = update.sequence;
         self.stats.count.set(self.items.len() as i64);
+    }
+
+    /// Saves the mempool transactions (e.g. on shutdown), so they won't be re-fetched on restart.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let items: Vec<SavedItem> = self
+            .items
+            .values()
+            .map(|item| SavedItem {
+                tx: serialize(&item.tx),
+                fee: item.entry.fee(),
+                vsize: item.entry.vsize(),
+            })
+            .collect();
+        let data = bincode::serialize(&(SNAPSHOT_VERSION, items))
+            .chain_err(|| "failed to serialize mempool")?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data).chain_err(|| format!("failed to write {:?}", tmp_path))?;
+        fs::rename(&tmp_path, path).chain_err(|| format!("failed to rename {:?}", tmp_path))?;
+        info!(
+            "saved {} mempool transactions to {:?}",
+            self.items.len(),
+            path
+        );
         Ok(())
     }
 
+    /// Loads the saved mempool transactions, returning how many were loaded.
+    /// The next update will remove the ones which are no longer in bitcoind's mempool.
+    pub fn load(&mut self, path: &Path) -> Result<usize> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == NotFound => return Ok(0),
+            Err(e) => return Err(e).chain_err(|| format!("failed to read {:?}", path)),
+        };
+        let (version, items): (u32, Vec<SavedItem>) =
+            bincode::deserialize(&data).chain_err(|| format!("invalid mempool at {:?}", path))?;
+        if version != SNAPSHOT_VERSION {
+            bail!("unsupported mempool version {} at {:?}", version, path);
+        }
+        let mut count = 0;
+        for item in items {
+            let tx: Transaction =
+                deserialize(&item.tx).chain_err(|| format!("invalid tx at {:?}", path))?;
+            if self.add_notified(tx, MempoolEntry::new(item.fee, item.vsize)) {
+                count += 1;
+            }
+        }
+        self.update_fee_histogram();
+        info!("loaded {} mempool transactions from {:?}", count, path);
+        Ok(count)
+    }
+
+    /// Adds a single (notified) transaction, returning `false` if it is already tracked.
+    /// Removals and fee histogram updates are handled by the next `apply()`.
+    pub fn add_notified(&mut self, tx: Transaction, entry: MempoolEntry) -> bool {
+        let txid = tx.txid();
+        if self.has_txn(&txid) {
+            return false;
+        }
+        self.add(&txid, tx, entry);
+        self.stats.count.set(self.items.len() as i64);
+        true
+    }
+
     fn add(&mut self, txid: &Txid, tx: Transaction, entry: MempoolEntry) {
         self.index.add(&tx);
         self.items.insert(*txid, Item { tx, entry });
@@ -284,6 +423,104 @@ fn electrum_fees(entries: &[&MempoolEntry]) -> Vec<(f32, u32)> {
 
 #[cfg(test)]
 mod tests {
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use bitcoin::hash_types::Txid;
+    use bitcoin::hashes::Hash;
+
+    use crate::daemon::MempoolEntry;
+    use crate::mempool::{Tracker, Update};
+    use crate::metrics::Metrics;
+
+    fn spending(txid: Txid) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint { txid, vout: 0 },
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_unconfirmed_inputs() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let mut tracker = Tracker::new(&metrics);
+        let parent = spending(Txid::from_inner([1; 32])); // spends a confirmed output
+        let child = spending(parent.txid());
+        let (parent_txid, child_txid) = (parent.txid(), child.txid());
+
+        tracker.add(&child_txid, child, MempoolEntry::new(1_000, 100));
+        assert!(!tracker.has_unconfirmed_inputs(&child_txid));
+
+        tracker.add(&parent_txid, parent, MempoolEntry::new(1_000, 100));
+        assert!(!tracker.has_unconfirmed_inputs(&parent_txid));
+        assert!(tracker.has_unconfirmed_inputs(&child_txid));
+
+        tracker.remove(&parent_txid); // e.g. after being confirmed
+        assert!(!tracker.has_unconfirmed_inputs(&child_txid));
+    }
+
+    #[test]
+    fn test_apply_update() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let mut tracker = Tracker::new(&metrics);
+        let tx = spending(Txid::from_inner([1; 32]));
+        let txid = tx.txid();
+        let notified = spending(Txid::from_inner([2; 32]));
+        let notified_txid = notified.txid();
+
+        let request = tracker.update_request(); // before the notification
+        assert!(tracker.add_notified(notified, MempoolEntry::new(1_000, 100)));
+        assert_eq!(request.txids.len(), 0);
+        tracker.apply(Update {
+            sequence: Some(1),
+            added: vec![(txid, tx, MempoolEntry::new(2_000, 100))],
+            removed: vec![],
+        });
+        assert!(tracker.has_txn(&txid));
+        assert!(tracker.has_txn(&notified_txid));
+        assert_eq!(tracker.update_request().sequence, Some(1));
+
+        for _ in 0..2 {
+            // e.g. concurrent updates, removing the same transaction
+            tracker.apply(Update {
+                sequence: Some(2),
+                added: vec![],
+                removed: vec![txid],
+            });
+        }
+        assert!(!tracker.has_txn(&txid));
+        assert_eq!(tracker.count(), 1);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("electrs-mempool-{}", std::process::id()));
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let mut tracker = Tracker::new(&metrics);
+        assert_eq!(tracker.load(&path).unwrap(), 0); // missing snapshot
+
+        let tx = spending(Txid::from_inner([1; 32]));
+        let txid = tx.txid();
+        tracker.add(&txid, tx, MempoolEntry::new(2_000, 100));
+        tracker.save(&path).unwrap();
+
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap()); // avoid duplicate metrics
+        let mut loaded = Tracker::new(&metrics);
+        assert_eq!(loaded.load(&path).unwrap(), 1);
+        assert!(loaded.has_txn(&txid));
+        assert_eq!(loaded.get_fee(&txid), Some(2_000));
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn test_fakestore() {
         use crate::daemon::MempoolEntry;
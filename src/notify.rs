@@ -1,70 +1,257 @@
-// TODO: network::socket::Socket needs to be reimplemented.
+//! A Bitcoin P2P client, for getting notified about new blocks and transactions as soon as
+//! bitcoind announces them (useful when ZMQ notifications are not available).
 
-use bitcoin::network::constants::Network;
-use bitcoin::network::message::NetworkMessage;
-use bitcoin::network::message_blockdata::InvType;
-use bitcoin::network::socket::Socket;
-use bitcoin::hash_types::Txid;
-use bitcoin::util::Error;
-
-use std::sync::mpsc::Sender;
+use bitcoin::consensus::encode::{serialize, Decodable};
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_network::VersionMessage;
+use error_chain::ChainedError;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::util::spawn_thread;
+use crate::zmq::Notification;
 
-use crate::util;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60); // a ping is sent after being idle
 
-fn connect() -> Result<Socket, Error> {
-    let mut sock = Socket::new(Network::Bitcoin);
-    sock.connect("127.0.0.1", 8333)?;
-    Ok(sock)
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    network: Network,
 }
 
-fn handle(mut sock: Socket, tx: Sender<Txid>) {
-    let mut outgoing = vec![sock.version_message(0).unwrap()];
-    loop {
-        for msg in outgoing.split_off(0) {
-            trace!("send {:?}", msg);
-            if let Err(e) = sock.send_message(msg.clone()) {
-                warn!("failed to connect to node: {}", e);
-                break;
+impl Connection {
+    fn connect(addr: SocketAddr, network: Network, idle_timeout: Duration) -> Result<Connection> {
+        let writer = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .chain_err(|| format!("failed to connect to {}", addr))?;
+        writer
+            .set_read_timeout(Some(idle_timeout))
+            .chain_err(|| "failed to set read timeout")?;
+        writer
+            .set_write_timeout(Some(idle_timeout))
+            .chain_err(|| "failed to set write timeout")?;
+        let reader = BufReader::new(writer.try_clone().chain_err(|| "failed to clone socket")?);
+        Ok(Connection {
+            reader,
+            writer,
+            network,
+        })
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<()> {
+        trace!("send {:?}", payload.cmd());
+        let msg = RawNetworkMessage {
+            magic: self.network.magic(),
+            payload,
+        };
+        self.writer
+            .write_all(&serialize(&msg))
+            .chain_err(|| "failed to send p2p message")
+    }
+
+    /// Waits for the next message, sending a ping if the connection is idle. Fails if nothing
+    /// (not even a pong) is received afterwards, so a half-open connection is re-connected.
+    fn wait(&mut self) -> Result<()> {
+        let mut pinged = false;
+        loop {
+            let result = self.reader.fill_buf().map(|buf| buf.is_empty());
+            match result {
+                Ok(true) => bail!("p2p connection closed"),
+                Ok(false) => return Ok(()),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if pinged {
+                        bail!("no p2p messages received after ping");
+                    }
+                    self.send(NetworkMessage::Ping(nonce()))?;
+                    pinged = true;
+                }
+                Err(e) => return Err(e).chain_err(|| "failed to receive p2p message"),
             }
         }
-        // Receive new message
-        let msg = match sock.receive_message() {
-            Ok(msg) => msg,
-            Err(e) => {
-                warn!("failed to receive p2p message: {}", e);
-                break;
+    }
+
+    fn recv(&mut self) -> Result<NetworkMessage> {
+        self.wait()?;
+        let msg = RawNetworkMessage::consensus_decode(&mut self.reader)
+            .chain_err(|| "failed to receive p2p message")?;
+        if msg.magic != self.network.magic() {
+            bail!("unexpected network magic {:#x}", msg.magic);
+        }
+        trace!("recv {:?}", msg.payload.cmd());
+        Ok(msg.payload)
+    }
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("invalid system time")
+}
+
+fn nonce() -> u64 {
+    now().subsec_nanos().into()
+}
+
+fn version_message(addr: SocketAddr) -> NetworkMessage {
+    let now = now();
+    let unspecified: SocketAddr = ([0, 0, 0, 0], 0).into();
+    let mut version = VersionMessage::new(
+        ServiceFlags::NONE,
+        now.as_secs() as i64,
+        Address::new(&addr, ServiceFlags::NONE),
+        Address::new(&unspecified, ServiceFlags::NONE),
+        nonce(),
+        format!("/electrs:{}/", env!("CARGO_PKG_VERSION")),
+        0, // start height
+    );
+    version.relay = true; // so transactions will be announced
+    NetworkMessage::Version(version)
+}
+
+fn run(addr: SocketAddr, network: Network, handler: &dyn Fn(Notification)) -> Result<()> {
+    let mut conn = Connection::connect(addr, network, IDLE_TIMEOUT)?;
+    conn.send(version_message(addr))?;
+    loop {
+        match conn.recv()? {
+            NetworkMessage::Version(version) => {
+                info!("connected to {} ({})", addr, version.user_agent);
+                conn.send(NetworkMessage::Verack)?;
             }
-        };
-        trace!("recv {:?}", msg);
-        match msg {
-            NetworkMessage::Alert(_) => continue, // deprecated
-            NetworkMessage::Version(_) => outgoing.push(NetworkMessage::Verack),
-            NetworkMessage::Ping(nonce) => outgoing.push(NetworkMessage::Pong(nonce)),
-            NetworkMessage::Inv(ref inventory) => {
-                inventory
-                    .iter()
-                    .filter(|inv| inv.inv_type == InvType::Block)
-                    .for_each(|inv| tx.send(inv.hash).expect("failed to send message"));
+            NetworkMessage::Ping(nonce) => conn.send(NetworkMessage::Pong(nonce))?,
+            NetworkMessage::Inv(inventory) => {
+                let mut wanted = vec![];
+                for inv in inventory {
+                    match inv {
+                        Inventory::Block(hash) | Inventory::WitnessBlock(hash) => {
+                            handler(Notification::Block(hash))
+                        }
+                        Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
+                            wanted.push(Inventory::WitnessTransaction(txid))
+                        }
+                        _ => (),
+                    }
+                }
+                if !wanted.is_empty() {
+                    conn.send(NetworkMessage::GetData(wanted))?;
+                }
             }
+            NetworkMessage::Tx(tx) => handler(Notification::Transaction(tx)),
             _ => (),
-        };
+        }
     }
 }
 
-pub fn run() -> util::Channel<Txid> {
-    let chan = util::Channel::new();
-    let tx = chan.sender();
-
-    util::spawn_thread("p2p", move || loop {
-        // TODO: support testnet and regtest as well.
-        match connect() {
-            Ok(sock) => handle(sock, tx.clone()),
-            Err(e) => warn!("p2p error: {}", e),
+/// Connects to bitcoind's P2P port (in a background thread), notifying about announced
+/// blocks and transactions. The connection is re-established if needed.
+pub fn start<F>(addr: SocketAddr, network: Network, handler: F)
+where
+    F: Fn(Notification) + Send + 'static,
+{
+    spawn_thread("p2p", move || loop {
+        if let Err(e) = run(addr, network, &handler) {
+            warn!("p2p connection to {} failed: {}", addr, e.display_chain());
         }
-        thread::sleep(Duration::from_secs(3));
+        thread::sleep(RECONNECT_DELAY);
     });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use crossbeam_channel as channel;
+    use std::net::TcpListener;
 
-    chan
+    // Stands in for bitcoind, announcing a block and a transaction.
+    fn serve(listener: TcpListener, network: Network) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            network,
+        };
+        let block = genesis_block(network);
+        match conn.recv().unwrap() {
+            NetworkMessage::Version(version) => assert!(version.relay),
+            msg => panic!("unexpected {:?}", msg.cmd()),
+        }
+        conn.send(version_message(listener.local_addr().unwrap()))
+            .unwrap();
+        match conn.recv().unwrap() {
+            NetworkMessage::Verack => (),
+            msg => panic!("unexpected {:?}", msg.cmd()),
+        }
+        let txid = block.txdata[0].txid();
+        let inventory = vec![
+            Inventory::Block(block.block_hash()),
+            Inventory::Transaction(txid),
+        ];
+        conn.send(NetworkMessage::Inv(inventory)).unwrap();
+        match conn.recv().unwrap() {
+            NetworkMessage::GetData(inventory) => {
+                assert_eq!(inventory, vec![Inventory::WitnessTransaction(txid)])
+            }
+            msg => panic!("unexpected {:?}", msg.cmd()),
+        }
+        conn.send(NetworkMessage::Tx(block.txdata[0].clone()))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_notifications() {
+        let network = Network::Regtest;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = thread::spawn(move || serve(listener, network));
+
+        let (sender, receiver) = channel::unbounded();
+        start(addr, network, move |notification| {
+            sender.send(notification).unwrap()
+        });
+        node.join().unwrap();
+
+        let block = genesis_block(network);
+        match receiver.recv().unwrap() {
+            Notification::Block(blockhash) => assert_eq!(blockhash, block.block_hash()),
+            _ => panic!("expected a block notification"),
+        }
+        match receiver.recv().unwrap() {
+            Notification::Transaction(tx) => assert_eq!(tx.txid(), block.txdata[0].txid()),
+            _ => panic!("expected a transaction notification"),
+        }
+    }
+
+    #[test]
+    fn test_idle_node() {
+        let network = Network::Regtest;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let node = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut conn = Connection {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+                network,
+            };
+            match conn.recv().unwrap() {
+                NetworkMessage::Ping(_) => (),
+                msg => panic!("unexpected {:?}", msg.cmd()),
+            }
+            conn // stays open (without replying), like a half-open connection
+        });
+        let mut conn = Connection::connect(addr, network, Duration::from_millis(100)).unwrap();
+        let err = conn.recv().unwrap_err();
+        assert_eq!(err.to_string(), "no p2p messages received after ping");
+        node.join().unwrap();
+    }
 }
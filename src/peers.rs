@@ -0,0 +1,716 @@
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::hex::FromHex;
+use error_chain::ChainedError;
+use serde_json::{from_str, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+const DEFAULT_TCP_PORT: u16 = 50001;
+const DEFAULT_SSL_PORT: u16 = 50002;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+const PEERS_PER_POLL: usize = 10; // limits the number of connections made during each poll
+const REVALIDATE_SECS: u64 = 6 * 3600; // validated peers are checked again after 6 hours
+const RETRY_SECS: u64 = 3600; // failed peers are retried after an hour
+const MAX_FAILURES: u32 = 3; // forget peers after too many consecutive failures
+const MAX_PEERS: usize = 1000;
+const MAX_ANNOUNCED: usize = 100; // limits the number of hosts waiting to be resolved
+pub const MAX_ADDED_PEERS: usize = 3; // per client connection (via `server.add_peer`)
+
+/// An Electrum server (configured as a seed, or announced by another server).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    pub host: String,
+    pub tcp_port: Option<u16>,
+    pub ssl_port: Option<u16>,
+}
+
+impl FromStr for Peer {
+    type Err = Error;
+
+    /// Parses 'HOST:PORT:t' (TCP) or 'HOST:PORT:s' (SSL), where the protocol defaults to TCP.
+    fn from_str(s: &str) -> Result<Peer> {
+        let mut parts: Vec<&str> = s.rsplitn(3, ':').collect();
+        parts.reverse();
+        let protocol = match parts.len() {
+            3 => parts.pop().unwrap(),
+            2 => "t",
+            _ => bail!("missing port in peer {:?}", s),
+        };
+        let port = parts[1]
+            .parse::<u16>()
+            .chain_err(|| format!("invalid port in peer {:?}", s))?;
+        let host = parts[0].trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            bail!("missing host in peer {:?}", s);
+        }
+        let (tcp_port, ssl_port) = match protocol {
+            "t" => (Some(port), None),
+            "s" => (None, Some(port)),
+            _ => bail!("invalid protocol in peer {:?} (should be 't' or 's')", s),
+        };
+        Ok(Peer {
+            host: host.to_lowercase(),
+            tcp_port,
+            ssl_port,
+        })
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.host)?;
+        if let Some(port) = self.tcp_port {
+            write!(f, " t{}", port)?;
+        }
+        if let Some(port) = self.ssl_port {
+            write!(f, " s{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the peers reported by a `server.features` result ('hosts' entry).
+fn peers_from_features(features: &Value) -> Result<Vec<Peer>> {
+    let hosts = features
+        .get("hosts")
+        .and_then(Value::as_object)
+        .chain_err(|| "missing hosts")?;
+    let mut peers = vec![];
+    for (host, ports) in hosts {
+        let port = |name: &str| match ports.get(name).and_then(Value::as_u64) {
+            Some(port) => u16::try_from(port)
+                .map(Some)
+                .chain_err(|| format!("invalid {} {} for {}", name, port, host)),
+            None => Ok(None),
+        };
+        let peer = Peer {
+            host: host.to_lowercase(),
+            tcp_port: port("tcp_port")?,
+            ssl_port: port("ssl_port")?,
+        };
+        if peer.tcp_port.is_some() || peer.ssl_port.is_some() {
+            peers.push(peer);
+        }
+    }
+    Ok(peers)
+}
+
+/// Returns the peer reported by a `server.peers.subscribe` result item ([ip, host, features]).
+fn peer_from_subscription(item: &Value) -> Option<Peer> {
+    let host = item.get(1)?.as_str()?;
+    let features = item.get(2)?.as_array()?;
+    let mut peer = Peer {
+        host: host.to_lowercase(),
+        tcp_port: None,
+        ssl_port: None,
+    };
+    // The port may be omitted (if it's the default one), but must be valid otherwise
+    let port = |feature: &str, default: u16| match &feature[1..] {
+        "" => Some(default),
+        port => port.parse::<u16>().ok(),
+    };
+    for feature in features.iter().filter_map(Value::as_str) {
+        if feature.starts_with('t') {
+            peer.tcp_port = Some(port(feature, DEFAULT_TCP_PORT)?);
+        } else if feature.starts_with('s') {
+            peer.ssl_port = Some(port(feature, DEFAULT_SSL_PORT)?);
+        }
+    }
+    if peer.tcp_port.is_none() && peer.ssl_port.is_none() {
+        return None;
+    }
+    Some(peer)
+}
+
+// IPv4-mapped IPv6 addresses (e.g. accepted by a dual-stack listener) are compared as IPv4 ones
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Returns true if the peer's host resolves to `ip` (so it is announced by the server itself).
+fn resolves_to(peer: &Peer, ip: IpAddr) -> bool {
+    let port = peer.tcp_port.or(peer.ssl_port).unwrap_or(DEFAULT_TCP_PORT);
+    match (peer.host.as_str(), port).to_socket_addrs() {
+        Ok(mut addrs) => addrs.any(|addr| canonical_ip(addr.ip()) == canonical_ip(ip)),
+        Err(e) => {
+            debug!("failed to resolve {}: {}", peer.host, e);
+            false
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("invalid system time")
+        .as_secs()
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Minimal Electrum RPC client, used for validating peers.
+struct Client {
+    stream: BufReader<Box<dyn Stream>>,
+    next_id: u64,
+}
+
+impl Client {
+    fn connect(peer: &Peer) -> Result<(Client, SocketAddr)> {
+        let (port, use_ssl) = match (peer.tcp_port, peer.ssl_port) {
+            (Some(port), _) => (port, false),
+            (None, Some(port)) => (port, true),
+            (None, None) => bail!("no ports for {}", peer),
+        };
+        let addr = (peer.host.as_str(), port)
+            .to_socket_addrs()
+            .chain_err(|| format!("failed to resolve {}", peer.host))?
+            .next()
+            .chain_err(|| format!("no address for {}", peer.host))?;
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .chain_err(|| format!("failed to connect to {}", addr))?;
+        stream
+            .set_read_timeout(Some(RPC_TIMEOUT))
+            .chain_err(|| "failed to set read timeout")?;
+        stream
+            .set_write_timeout(Some(RPC_TIMEOUT))
+            .chain_err(|| "failed to set write timeout")?;
+        let stream: Box<dyn Stream> = if use_ssl {
+            Client::tls_connect(&peer.host, stream)?
+        } else {
+            Box::new(stream)
+        };
+        let client = Client {
+            stream: BufReader::new(stream),
+            next_id: 0,
+        };
+        Ok((client, addr))
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_connect(host: &str, stream: TcpStream) -> Result<Box<dyn Stream>> {
+        // Electrum servers commonly use self-signed certificates
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true)
+            .build()
+            .chain_err(|| "failed to create TLS connector")?;
+        let stream = connector
+            .connect(host, stream)
+            .map_err(|err| format!("TLS handshake with {} failed: {}", host, err))?;
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn tls_connect(host: &str, _stream: TcpStream) -> Result<Box<dyn Stream>> {
+        bail!("{} supports only SSL (requires the 'tls' feature)", host)
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        self.stream
+            .get_mut()
+            .write_all((request.to_string() + "\n").as_bytes())
+            .chain_err(|| format!("failed to send {}", method))?;
+        loop {
+            let mut line = String::new();
+            self.stream
+                .read_line(&mut line)
+                .chain_err(|| format!("failed to receive {} reply", method))?;
+            if line.is_empty() {
+                bail!("connection closed during {}", method);
+            }
+            let reply: Value = from_str(&line).chain_err(|| ErrorKind::ParseError)?;
+            if reply.get("id") != Some(&json!(id)) {
+                continue; // skip notifications
+            }
+            match reply.get("error") {
+                None | Some(Value::Null) => (),
+                Some(error) => bail!("{} failed: {}", method, error),
+            }
+            return Ok(reply.get("result").cloned().unwrap_or(Value::Null));
+        }
+    }
+}
+
+/// The result of a successful peer validation.
+struct Validated {
+    ip: String,
+    features: Value,
+    discovered: Vec<Peer>,
+}
+
+/// Connects to `peer`, and makes sure it serves the same chain (announcing `own_features`,
+/// if this server has a public hostname).
+fn validate(
+    peer: &Peer,
+    genesis_hash: &BlockHash,
+    own_features: &Value,
+    announce: bool,
+) -> Result<Validated> {
+    let (mut client, addr) = Client::connect(peer)?;
+    let version = own_features["server_version"].clone();
+    let protocols = json!([own_features["protocol_min"], own_features["protocol_max"]]);
+    client.call("server.version", json!([version, protocols]))?;
+
+    let features = client.call("server.features", json!([]))?;
+    let peer_genesis_hash = features
+        .get("genesis_hash")
+        .and_then(Value::as_str)
+        .chain_err(|| "missing genesis_hash")?;
+    let peer_genesis_hash =
+        BlockHash::from_hex(peer_genesis_hash).chain_err(|| "invalid genesis_hash")?;
+    if peer_genesis_hash != *genesis_hash {
+        bail!(
+            "{} is on a different chain (genesis {})",
+            peer,
+            peer_genesis_hash
+        );
+    }
+    if !peers_from_features(&features)?
+        .iter()
+        .any(|p| p.host == peer.host)
+    {
+        bail!("{} is not listed in its own hosts", peer.host);
+    }
+    if announce {
+        if let Err(e) = client.call("server.add_peer", json!([own_features])) {
+            debug!("failed to announce to {}: {}", peer, e);
+        }
+    }
+    let discovered = match client.call("server.peers.subscribe", json!([]))? {
+        Value::Array(items) => items.iter().filter_map(peer_from_subscription).collect(),
+        _ => vec![],
+    };
+    Ok(Validated {
+        ip: addr.ip().to_string(),
+        features,
+        discovered,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerEntry {
+    peer: Peer,
+    ip: Option<String>,
+    features: Option<Value>, // from the last successful validation
+    last_good: Option<u64>,  // UNIX timestamp
+    last_try: Option<u64>,   // UNIX timestamp
+    failures: u32,
+}
+
+impl PeerEntry {
+    fn new(peer: Peer) -> PeerEntry {
+        PeerEntry {
+            peer,
+            ip: None,
+            features: None,
+            last_good: None,
+            last_try: None,
+            failures: 0,
+        }
+    }
+
+    fn needs_validation(&self, now: u64) -> bool {
+        let interval = if self.failures > 0 {
+            RETRY_SECS
+        } else {
+            REVALIDATE_SECS
+        };
+        self.last_try.map_or(true, |t| t + interval <= now)
+    }
+
+    fn is_good(&self) -> bool {
+        self.last_good.is_some() && self.failures == 0
+    }
+
+    // Formatted as a `server.peers.subscribe` result item
+    fn to_json(&self) -> Value {
+        let features = self.features.as_ref();
+        let mut result = vec![];
+        if let Some(version) = features.and_then(|f| f["protocol_max"].as_str()) {
+            result.push(format!("v{}", version));
+        }
+        if let Some(port) = self.peer.ssl_port {
+            result.push(format!("s{}", port));
+        }
+        if let Some(port) = self.peer.tcp_port {
+            result.push(format!("t{}", port));
+        }
+        if let Some(pruning) = features.and_then(|f| f["pruning"].as_u64()) {
+            result.push(format!("p{}", pruning));
+        }
+        let ip = self.ip.as_ref().unwrap_or(&self.peer.host);
+        json!([ip, self.peer.host, result])
+    }
+}
+
+/// Discovers and validates other Electrum servers, persisting them in a JSON file.
+pub struct PeerManager {
+    path: PathBuf,
+    genesis_hash: BlockHash,
+    own_features: Value,
+    entries: Mutex<HashMap<String, PeerEntry>>, // by hostname
+    announced: Mutex<Vec<(Peer, IpAddr)>>,      // via `server.add_peer`, resolved by `poll()`
+}
+
+impl PeerManager {
+    /// Loads the known peers from `path` (if exists), adding the `seeds`.
+    pub fn new(
+        path: &Path,
+        seeds: &[Peer],
+        genesis_hash: BlockHash,
+        own_features: Value,
+    ) -> Arc<PeerManager> {
+        let entries: HashMap<String, PeerEntry> = match fs::read(path) {
+            Ok(data) => match serde_json::from_slice::<Vec<PeerEntry>>(&data) {
+                Ok(entries) => entries
+                    .into_iter()
+                    .map(|entry| (entry.peer.host.clone(), entry))
+                    .collect(),
+                Err(e) => {
+                    warn!("ignoring invalid peers file {:?}: {}", path, e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(), // no peers were saved yet
+        };
+        let manager = Arc::new(PeerManager {
+            path: path.to_path_buf(),
+            genesis_hash,
+            own_features,
+            entries: Mutex::new(entries),
+            announced: Mutex::new(vec![]),
+        });
+        for seed in seeds {
+            manager.add(seed.clone());
+        }
+        manager
+    }
+
+    /// This server's `server.features` result.
+    pub fn features(&self) -> &Value {
+        &self.own_features
+    }
+
+    fn is_own_host(&self, host: &str) -> bool {
+        match self.own_features["hosts"].as_object() {
+            Some(hosts) => hosts.keys().any(|h| h.eq_ignore_ascii_case(host)),
+            None => false,
+        }
+    }
+
+    fn add(&self, peer: Peer) -> bool {
+        if self.is_own_host(&peer.host) {
+            return false;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&peer.host) {
+            if entry.peer != peer {
+                entry.peer = peer; // ports have changed, so it should be validated again
+                entry.last_try = None;
+            }
+            return true;
+        }
+        if entries.len() >= MAX_PEERS {
+            return false;
+        }
+        debug!("adding peer {}", peer);
+        entries.insert(peer.host.clone(), PeerEntry::new(peer));
+        true
+    }
+
+    /// Handles `server.add_peer` from a client connected from `addr`: up to `limit` of the
+    /// announced hosts are queued, to be added (and validated) by the background thread if they
+    /// resolve to the client's IP - so the client doesn't wait for DNS resolution.
+    /// Returns the number of queued hosts.
+    pub fn add_features(&self, features: &Value, addr: &SocketAddr, limit: usize) -> Result<usize> {
+        let genesis_hash = features
+            .get("genesis_hash")
+            .and_then(Value::as_str)
+            .chain_err(|| "missing genesis_hash")?;
+        if BlockHash::from_hex(genesis_hash).chain_err(|| "invalid genesis_hash")?
+            != self.genesis_hash
+        {
+            return Ok(0);
+        }
+        let peers = peers_from_features(features)?;
+        let mut announced = self.announced.lock().unwrap();
+        let mut added = 0;
+        for peer in peers {
+            if added == limit || announced.len() >= MAX_ANNOUNCED {
+                break;
+            }
+            if self.is_own_host(&peer.host) {
+                continue;
+            }
+            announced.push((peer, addr.ip()));
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    // Adds the announced hosts, which resolve to the IP they were announced from
+    fn add_announced(&self) {
+        let announced = std::mem::take(&mut *self.announced.lock().unwrap());
+        for (peer, ip) in announced {
+            if resolves_to(&peer, ip) {
+                self.add(peer);
+            } else {
+                debug!("{} is not announced by {}", peer, ip);
+            }
+        }
+    }
+
+    /// Returns the validated peers (as `server.peers.subscribe` result).
+    pub fn subscribe(&self) -> Value {
+        let entries = self.entries.lock().unwrap();
+        let mut peers: Vec<&PeerEntry> = entries.values().filter(|e| e.is_good()).collect();
+        peers.sort_by(|a, b| a.peer.host.cmp(&b.peer.host));
+        Value::Array(peers.into_iter().map(PeerEntry::to_json).collect())
+    }
+
+    fn poll(&self) {
+        self.add_announced();
+        let now = now();
+        let peers: Vec<Peer> = {
+            let entries = self.entries.lock().unwrap();
+            entries
+                .values()
+                .filter(|entry| entry.needs_validation(now))
+                .take(PEERS_PER_POLL)
+                .map(|entry| entry.peer.clone())
+                .collect()
+        };
+        if peers.is_empty() {
+            return;
+        }
+        let announce = self.own_features["hosts"]
+            .as_object()
+            .map_or(false, |hosts| !hosts.is_empty());
+        for peer in peers {
+            let result = validate(&peer, &self.genesis_hash, &self.own_features, announce);
+            let discovered = {
+                let mut entries = self.entries.lock().unwrap();
+                let entry = match entries.get_mut(&peer.host) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                entry.last_try = Some(now);
+                match result {
+                    Ok(validated) => {
+                        debug!("validated peer {} ({})", peer, validated.ip);
+                        entry.ip = Some(validated.ip);
+                        entry.features = Some(validated.features);
+                        entry.last_good = Some(now);
+                        entry.failures = 0;
+                        validated.discovered
+                    }
+                    Err(e) => {
+                        debug!("failed to validate peer {}: {}", peer, e);
+                        entry.failures += 1;
+                        if entry.failures >= MAX_FAILURES {
+                            info!("forgetting peer {}", peer);
+                            entries.remove(&peer.host);
+                        }
+                        vec![]
+                    }
+                }
+            };
+            for peer in discovered {
+                self.add(peer);
+            }
+        }
+        if let Err(e) = self.save() {
+            warn!("failed to save peers: {}", e.display_chain());
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let data = {
+            let entries = self.entries.lock().unwrap();
+            let entries: Vec<&PeerEntry> = entries.values().collect();
+            serde_json::to_vec(&entries).chain_err(|| "failed to serialize peers")?
+        };
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, data).chain_err(|| format!("failed to write {:?}", tmp_path))?;
+        fs::rename(&tmp_path, &self.path).chain_err(|| format!("failed to rename {:?}", tmp_path))
+    }
+
+    /// Validates the known peers periodically (in a background thread).
+    pub fn start(manager: &Arc<PeerManager>) {
+        let manager = Arc::clone(manager);
+        spawn_thread("peers", move || loop {
+            manager.poll();
+            thread::sleep(POLL_INTERVAL);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn genesis_hash() -> BlockHash {
+        BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+            .unwrap()
+    }
+
+    fn features(host: &str, port: u16) -> Value {
+        json!({
+            "genesis_hash": genesis_hash().to_string(),
+            "hosts": {host: {"tcp_port": port, "ssl_port": null}},
+            "protocol_min": "1.4",
+            "protocol_max": "1.4",
+            "server_version": "test 0.1",
+            "hash_function": "sha256",
+            "pruning": null,
+        })
+    }
+
+    // A stand-in Electrum server, handling a single connection
+    fn start_server(features: Value, peers: Value) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let request: Value = from_str(&line.unwrap()).unwrap();
+                let result = match request["method"].as_str().unwrap() {
+                    "server.version" => json!(["test 0.1", "1.4"]),
+                    "server.features" => features.clone(),
+                    "server.peers.subscribe" => peers.clone(),
+                    "server.add_peer" => json!(true),
+                    _ => Value::Null,
+                };
+                let reply = json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+                writer
+                    .write_all((reply.to_string() + "\n").as_bytes())
+                    .unwrap();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_parse_peer() {
+        let peer: Peer = "Electrum.Example.com:50002:s".parse().unwrap();
+        assert_eq!(peer.host, "electrum.example.com");
+        assert_eq!((peer.tcp_port, peer.ssl_port), (None, Some(50002)));
+        let peer: Peer = "[::1]:50001".parse().unwrap();
+        assert_eq!(peer.host, "::1");
+        assert_eq!((peer.tcp_port, peer.ssl_port), (Some(50001), None));
+        assert!("localhost".parse::<Peer>().is_err());
+        assert!("localhost:50001:x".parse::<Peer>().is_err());
+        assert!("localhost:65536".parse::<Peer>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_ports() {
+        let mut announced = features("example.com", 50001);
+        assert_eq!(peers_from_features(&announced).unwrap().len(), 1);
+        announced["hosts"]["example.com"]["ssl_port"] = json!(65536 + 50002);
+        assert!(peers_from_features(&announced).is_err());
+
+        let item = |port: &str| json!(["10.0.0.1", "example.com", ["v1.4", port]]);
+        let peer = peer_from_subscription(&item("t")).unwrap();
+        assert_eq!(peer.tcp_port, Some(DEFAULT_TCP_PORT));
+        assert!(peer_from_subscription(&item("t65536")).is_none());
+        assert!(peer_from_subscription(&item("sx")).is_none());
+    }
+
+    #[test]
+    fn test_validate_peer() {
+        let discovered = json!([["10.0.0.1", "other.example.com", ["v1.4", "s50002", "t"]]]);
+        let port = start_server(features("127.0.0.1", 0), discovered);
+        let peer: Peer = format!("127.0.0.1:{}:t", port).parse().unwrap();
+        let own_features = features("own.example.com", 50001);
+        let validated = validate(&peer, &genesis_hash(), &own_features, true).unwrap();
+        assert_eq!(validated.ip, "127.0.0.1");
+        assert_eq!(
+            validated.discovered,
+            vec![Peer {
+                host: "other.example.com".to_owned(),
+                tcp_port: Some(DEFAULT_TCP_PORT),
+                ssl_port: Some(50002),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reject_other_chain() {
+        let mut other_features = features("127.0.0.1", 0);
+        other_features["genesis_hash"] = json!(BlockHash::default().to_string());
+        let port = start_server(other_features, json!([]));
+        let peer: Peer = format!("127.0.0.1:{}:t", port).parse().unwrap();
+        let own_features = features("own.example.com", 50001);
+        assert!(validate(&peer, &genesis_hash(), &own_features, false).is_err());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let path = std::env::temp_dir().join(format!("electrs-peers-{}", std::process::id()));
+        let seed: Peer = "10.0.0.3:1:t".parse().unwrap();
+        let manager = PeerManager::new(
+            &path,
+            &[seed],
+            genesis_hash(),
+            features("own.example.com", 50001),
+        );
+        assert_eq!(manager.subscribe(), json!([]));
+        let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        let add = |host: &str, addr: &SocketAddr, limit| {
+            manager.add_features(&features(host, 50001), addr, limit)
+        };
+        assert_eq!(add("own.example.com", &addr, 1).unwrap(), 0);
+        // the announced host must resolve to the client's IP
+        let other_addr: SocketAddr = "10.0.0.1:12345".parse().unwrap();
+        assert_eq!(add("127.0.0.1", &other_addr, 1).unwrap(), 1);
+        manager.add_announced();
+        assert!(!manager.entries.lock().unwrap().contains_key("127.0.0.1"));
+        assert_eq!(add("127.0.0.1", &addr, 0).unwrap(), 0);
+        let mapped_addr: SocketAddr = "[::ffff:127.0.0.1]:12345".parse().unwrap();
+        assert_eq!(add("127.0.0.1", &mapped_addr, 1).unwrap(), 1);
+        manager.add_announced();
+        assert!(manager.announced.lock().unwrap().is_empty());
+        {
+            let mut entries = manager.entries.lock().unwrap();
+            let entry = entries.get_mut("127.0.0.1").unwrap();
+            entry.ip = Some("10.0.0.2".to_owned());
+            entry.features = Some(features("127.0.0.1", 50001));
+            entry.last_good = Some(now());
+        }
+        assert_eq!(
+            manager.subscribe(),
+            json!([["10.0.0.2", "127.0.0.1", ["v1.4", "t50001"]]])
+        );
+        manager.save().unwrap();
+        let loaded = PeerManager::new(&path, &[], genesis_hash(), json!({}));
+        assert_eq!(loaded.subscribe(), manager.subscribe());
+        fs::remove_file(&path).unwrap();
+    }
+}
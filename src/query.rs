@@ -1,18 +1,23 @@
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::deserialize;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hash_types::{BlockHash, TxMerkleNode, Txid};
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::Hash;
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
+use bitcoin::util::amount::Amount;
 use serde_json::Value;
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 use std::sync::{Arc, RwLock};
 
 use crate::app::App;
 use crate::cache::TransactionCache;
 use crate::errors::*;
-use crate::index::{compute_script_hash, TxInRow, TxOutRow, TxRow};
+use crate::index::{compute_script_hash, FundingRow, RowFormat, TxInRow, TxOutRow, TxRow};
 use crate::mempool::Tracker;
 use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
 use crate::store::{ReadStore, Row};
@@ -38,6 +43,8 @@ pub struct Status {
     confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
     mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
     txn_fees: HashMap<Txid, u64>,
+    unconfirmed_parents: HashSet<Txid>, // mempool txns spending other mempool txns' outputs
+    block_positions: HashMap<Txid, usize>, // for confirmed txns sharing a block with others
 }
 
 fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>)) -> i64 {
@@ -92,13 +99,28 @@ impl Status {
         }
         let mut items: Vec<HistoryItem> = txns_map
             .into_iter()
-            .map(|item| HistoryItem {
-                height: item.1,
-                tx_hash: item.0,
-                fee: self.txn_fees.get(&item.0).cloned(),
+            .map(|(txid, height)| HistoryItem {
+                // mempool txns with unconfirmed inputs are reported at height -1
+                height: if self.unconfirmed_parents.contains(&txid) {
+                    -1
+                } else {
+                    height
+                },
+                tx_hash: txid,
+                fee: self.txn_fees.get(&txid).cloned(),
             })
             .collect();
-        items.sort_unstable_by_key(|item| item.height);
+        // Confirmed txns go first (in blockchain order), followed by the mempool ones (height 0
+        // before -1). The txid is used as a tie-breaker, so that the order is deterministic.
+        items.sort_unstable_by_key(|item| {
+            let position = self.block_positions.get(&item.tx_hash).cloned();
+            (
+                item.height <= 0,
+                item.height.abs(),
+                position.unwrap_or(0),
+                item.tx_hash,
+            )
+        });
         items
     }
 
@@ -135,6 +157,63 @@ impl Status {
     }
 }
 
+/// Returns up to `limit` history items after `after_txid` (the confirmed ones, grouped by height,
+/// followed by the sorted mempool ones), together with the total number of items.
+/// `block` is called only for the blocks being paged, returning the positions of the block's
+/// txids which are part of the history (so unrelated candidates can be dropped).
+fn history_page<F>(
+    confirmed: BTreeMap<u32, HashSet<Txid>>,
+    mempool: Vec<HistoryItem>,
+    after_txid: Option<&Txid>,
+    limit: usize,
+    mut block: F,
+) -> Result<(Vec<HistoryItem>, usize)>
+where
+    F: FnMut(u32, &HashSet<Txid>) -> Result<HashMap<Txid, usize>>,
+{
+    let total = confirmed.values().map(HashSet::len).sum::<usize>() + mempool.len();
+    if let Some(txid) = after_txid {
+        if !confirmed.values().any(|txids| txids.contains(txid))
+            && !mempool.iter().any(|item| item.tx_hash == *txid)
+        {
+            bail!("unknown after_txid {}", txid);
+        }
+    }
+    let mut skipping = after_txid.is_some();
+    let mut page = vec![];
+    for (height, txids) in confirmed {
+        if page.len() == limit {
+            return Ok((page, total));
+        }
+        if skipping && !txids.contains(after_txid.unwrap()) {
+            continue; // no need to load this block's txids
+        }
+        let positions = block(height, &txids)?;
+        let mut txids: Vec<Txid> = positions.keys().cloned().collect();
+        txids.sort_unstable_by_key(|txid| (positions[txid], *txid));
+        let items = txids.into_iter().map(|txid| HistoryItem {
+            height: height as i32,
+            tx_hash: txid,
+            fee: None,
+        });
+        for item in items {
+            if skipping {
+                skipping = after_txid != Some(&item.tx_hash);
+            } else if page.len() < limit {
+                page.push(item);
+            }
+        }
+    }
+    for item in mempool {
+        if skipping {
+            skipping = after_txid != Some(&item.tx_hash);
+        } else if page.len() < limit {
+            page.push(item);
+        }
+    }
+    Ok((page, total))
+}
+
 struct TxnHeight {
     txn: Transaction,
     height: u32,
@@ -163,14 +242,109 @@ fn create_merkle_branch_and_root<T: Hash>(mut hashes: Vec<T>, mut index: usize)
     (merkle, hashes[0])
 }
 
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pk() {
+        "pubkey"
+    } else if script.is_p2pkh() {
+        "pubkeyhash"
+    } else if script.is_p2sh() {
+        "scripthash"
+    } else if script.is_v0_p2wpkh() {
+        "witness_v0_keyhash"
+    } else if script.is_v0_p2wsh() {
+        "witness_v0_scripthash"
+    } else if script.is_witness_program() {
+        "witness_unknown"
+    } else if script.is_op_return() {
+        "nulldata"
+    } else {
+        "nonstandard"
+    }
+}
+
+/// Builds `getrawtransaction`-like verbose JSON, without depending on bitcoind's version.
+/// `header` is the confirming block's header (`None` for mempool transactions).
+fn verbose_transaction(
+    txn: &Transaction,
+    header: Option<&HeaderEntry>,
+    tip_height: usize,
+    network: Network,
+) -> Value {
+    let vin: Vec<Value> = txn
+        .input
+        .iter()
+        .map(|txin| {
+            let mut value = if txn.is_coin_base() {
+                json!({ "coinbase": txin.script_sig.to_hex() })
+            } else {
+                json!({
+                    "txid": txin.previous_output.txid,
+                    "vout": txin.previous_output.vout,
+                    "scriptSig": { "hex": txin.script_sig.to_hex() },
+                })
+            };
+            if !txin.witness.is_empty() {
+                let witness: Vec<String> = txin.witness.iter().map(|item| item.to_hex()).collect();
+                value["txinwitness"] = json!(witness);
+            }
+            value["sequence"] = json!(txin.sequence);
+            value
+        })
+        .collect();
+    let vout: Vec<Value> = txn
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, txout)| {
+            let mut script_pubkey = json!({
+                "hex": txout.script_pubkey.to_hex(),
+                "type": script_type(&txout.script_pubkey),
+            });
+            if let Some(address) = Address::from_script(&txout.script_pubkey, network) {
+                script_pubkey["address"] = json!(address.to_string());
+            }
+            json!({
+                "value": Amount::from_sat(txout.value).as_btc(),
+                "n": n,
+                "scriptPubKey": script_pubkey,
+            })
+        })
+        .collect();
+    let raw = serialize(txn);
+    let weight = txn.get_weight();
+    let mut value = json!({
+        "txid": txn.txid(),
+        "hash": txn.wtxid(),
+        "version": txn.version,
+        "size": raw.len(),
+        "vsize": (weight + 3) / 4,
+        "weight": weight,
+        "locktime": txn.lock_time,
+        "vin": vin,
+        "vout": vout,
+        "hex": raw.to_hex(),
+        "confirmations": 0,
+    });
+    if let Some(header) = header {
+        let time = header.header().time;
+        value["blockhash"] = json!(header.hash());
+        value["confirmations"] = json!((tip_height + 1).saturating_sub(header.height()));
+        value["time"] = json!(time);
+        value["blocktime"] = json!(time);
+    }
+    value
+}
+
 // TODO: the functions below can be part of ReadStore.
-fn txrow_by_txid(store: &dyn ReadStore, txid: &Txid) -> Option<TxRow> {
+fn txrow_by_txid(store: &dyn ReadStore, txid: &Txid) -> Result<Option<TxRow>> {
     let key = TxRow::filter_full(&txid);
-    let value = store.get(&key)?;
-    Some(TxRow::from_row(&Row { key, value }))
+    match store.get(&key) {
+        Some(value) => Ok(Some(TxRow::from_row(&Row { key, value })?)),
+        None => Ok(None),
+    }
 }
 
-fn txrows_by_prefix(store: &dyn ReadStore, txid_prefix: HashPrefix) -> Vec<TxRow> {
+fn txrows_by_prefix(store: &dyn ReadStore, txid_prefix: HashPrefix) -> Result<Vec<TxRow>> {
     store
         .scan(&TxRow::filter_prefix(txid_prefix))
         .iter()
@@ -178,11 +352,70 @@ fn txrows_by_prefix(store: &dyn ReadStore, txid_prefix: HashPrefix) -> Vec<TxRow
         .collect()
 }
 
-fn txids_by_script_hash(store: &dyn ReadStore, script_hash: &[u8]) -> Vec<HashPrefix> {
+fn txrows_by_prefixes(
+    store: &dyn ReadStore,
+    prefixes: Vec<HashPrefix>,
+    max_height: Option<u32>,
+) -> Result<Vec<TxRow>> {
+    let mut tx_rows = vec![];
+    for txid_prefix in prefixes {
+        for tx_row in txrows_by_prefix(store, txid_prefix)? {
+            if is_within(tx_row.height, max_height) {
+                tx_rows.push(tx_row);
+            }
+        }
+    }
+    Ok(tx_rows)
+}
+
+fn txids_by_script_hash(store: &dyn ReadStore, script_hash: &[u8]) -> Result<Vec<HashPrefix>> {
     store
         .scan(&TxOutRow::filter(script_hash))
         .iter()
-        .map(|row| TxOutRow::from_row(row).txid_prefix)
+        .map(|row| TxOutRow::from_row(row).map(|row| row.txid_prefix))
+        .collect()
+}
+
+fn funding_rows_by_script_hash(
+    store: &dyn ReadStore,
+    script_hash: &[u8],
+) -> Result<Vec<FundingRow>> {
+    store
+        .scan(&TxOutRow::filter(script_hash))
+        .iter()
+        .map(|row| FundingRow::from_row(row))
+        .collect()
+}
+
+// Used for limiting queries to transactions confirmed up to `max_height` (if specified)
+fn is_within(height: u32, max_height: Option<u32>) -> bool {
+    max_height.map_or(true, |max_height| height <= max_height)
+}
+
+// Groups the confirmed txns (funding or spending) by their height, starting at `min_height`
+fn txids_by_height(
+    (funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>),
+    min_height: u32,
+) -> BTreeMap<u32, HashSet<Txid>> {
+    let mut txids_by_height = BTreeMap::<u32, HashSet<Txid>>::new();
+    let funding_txns = funding.iter().map(|f| (f.height, f.txn_id));
+    let spending_txns = spending.iter().map(|s| (s.height, s.txn_id));
+    for (height, txid) in funding_txns.chain(spending_txns) {
+        if height >= min_height {
+            txids_by_height
+                .entry(height)
+                .or_insert_with(HashSet::new)
+                .insert(txid);
+        }
+    }
+    txids_by_height
+}
+
+fn txids_by_funding_txn(store: &dyn ReadStore, txn_id: &Txid) -> Result<Vec<HashPrefix>> {
+    store
+        .scan(&TxInRow::filter_prefix(&txn_id))
+        .iter()
+        .map(|row| TxInRow::from_row(row).map(|row| row.txid_prefix))
         .collect()
 }
 
@@ -190,11 +423,11 @@ fn txids_by_funding_output(
     store: &dyn ReadStore,
     txn_id: &Txid,
     output_index: usize,
-) -> Vec<HashPrefix> {
+) -> Result<Vec<HashPrefix>> {
     store
         .scan(&TxInRow::filter(&txn_id, output_index))
         .iter()
-        .map(|row| TxInRow::from_row(row).txid_prefix)
+        .map(|row| TxInRow::from_row(row).map(|row| row.txid_prefix))
         .collect()
 }
 
@@ -225,33 +458,38 @@ impl Query {
         })
     }
 
+    fn load_txns(&self, tx_rows: Vec<TxRow>) -> Result<Vec<TxnHeight>> {
+        let mut txns = vec![];
+        for tx_row in tx_rows {
+            let txid: Txid = deserialize(&tx_row.key.txid).unwrap();
+            let txn = self.load_txn(&txid, Some(tx_row.height))?;
+            txns.push(TxnHeight {
+                txn,
+                height: tx_row.height,
+            })
+        }
+        Ok(txns)
+    }
+
     fn load_txns_by_prefix(
         &self,
         store: &dyn ReadStore,
         prefixes: Vec<HashPrefix>,
+        max_height: Option<u32>,
     ) -> Result<Vec<TxnHeight>> {
-        let mut txns = vec![];
-        for txid_prefix in prefixes {
-            for tx_row in txrows_by_prefix(store, txid_prefix) {
-                let txid: Txid = deserialize(&tx_row.key.txid).unwrap();
-                let txn = self.load_txn(&txid, Some(tx_row.height))?;
-                txns.push(TxnHeight {
-                    txn,
-                    height: tx_row.height,
-                })
-            }
-        }
-        Ok(txns)
+        self.load_txns(txrows_by_prefixes(store, prefixes, max_height)?)
     }
 
     fn find_spending_input(
         &self,
         store: &dyn ReadStore,
         funding: &FundingOutput,
+        max_height: Option<u32>,
     ) -> Result<Option<SpendingInput>> {
         let spending_txns: Vec<TxnHeight> = self.load_txns_by_prefix(
             store,
-            txids_by_funding_output(store, &funding.txn_id, funding.output_index),
+            txids_by_funding_output(store, &funding.txn_id, funding.output_index)?,
+            max_height,
         )?;
         let mut spending_inputs = vec![];
         for t in &spending_txns {
@@ -292,26 +530,119 @@ impl Query {
         result
     }
 
+    // Uses the extended rows, falling back to loading the transactions on txid prefix collisions
+    fn find_indexed_spending_input(
+        &self,
+        store: &dyn ReadStore,
+        funding: &FundingOutput,
+        max_height: Option<u32>,
+    ) -> Result<Option<SpendingInput>> {
+        let mut txid_prefixes: Vec<HashPrefix> = vec![];
+        for row in store.scan(&TxInRow::filter(&funding.txn_id, funding.output_index)) {
+            if TxInRow::funding_vout(&row)? as usize == funding.output_index {
+                txid_prefixes.push(TxInRow::from_row(&row)?.txid_prefix);
+            }
+        }
+        if txid_prefixes.len() > 1 {
+            return self.find_spending_input(store, funding, max_height);
+        }
+        let txid_prefix = match txid_prefixes.first() {
+            Some(txid_prefix) => *txid_prefix,
+            None => return Ok(None),
+        };
+        let mut tx_rows = txrows_by_prefix(store, txid_prefix)?;
+        if tx_rows.len() != 1 {
+            return self.find_spending_input(store, funding, max_height);
+        }
+        let tx_row = tx_rows.remove(0);
+        if !is_within(tx_row.height, max_height) {
+            return Ok(None);
+        }
+        Ok(Some(SpendingInput {
+            txn_id: deserialize(&tx_row.key.txid).unwrap(),
+            height: tx_row.height,
+            funding_output: (funding.txn_id, funding.output_index),
+            value: funding.value,
+        }))
+    }
+
+    // Answers the query from the extended rows (without fetching transactions from bitcoind)
+    fn indexed_status(
+        &self,
+        script_hash: &[u8],
+        max_height: Option<u32>,
+    ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
+        let mut funding = vec![];
+        let mut spending = vec![];
+        let read_store = self.app.read_store();
+        let mut ambiguous_prefixes = HashSet::new();
+        for row in funding_rows_by_script_hash(read_store, script_hash)? {
+            match row.script_hash {
+                Some(row_script_hash) if row_script_hash[..] != *script_hash => continue,
+                Some(_) => (),
+                None => {
+                    // the script hash prefix may collide, so the transactions have to be checked
+                    ambiguous_prefixes.insert(row.key.txid_prefix);
+                    continue;
+                }
+            }
+            let mut tx_rows = txrows_by_prefix(read_store, row.key.txid_prefix)?;
+            if tx_rows.len() != 1 {
+                ambiguous_prefixes.insert(row.key.txid_prefix);
+                continue;
+            }
+            let tx_row = tx_rows.remove(0);
+            if !is_within(tx_row.height, max_height) {
+                continue;
+            }
+            funding.push(FundingOutput {
+                txn_id: deserialize(&tx_row.key.txid).unwrap(),
+                height: tx_row.height,
+                output_index: row.key.output_index as usize,
+                value: row.value,
+            });
+        }
+        let prefixes = ambiguous_prefixes.into_iter().collect();
+        for t in self.load_txns_by_prefix(read_store, prefixes, max_height)? {
+            funding.extend(self.find_funding_outputs(&t, script_hash));
+        }
+        for funding_output in &funding {
+            if let Some(spent) =
+                self.find_indexed_spending_input(read_store, &funding_output, max_height)?
+            {
+                spending.push(spent);
+            }
+        }
+        Ok((funding, spending))
+    }
+
     fn confirmed_status(
         &self,
         script_hash: &[u8],
+        max_height: Option<u32>,
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
+        if self.app.index().row_format() == RowFormat::Extended {
+            return self.indexed_status(script_hash, max_height);
+        }
         let mut funding = vec![];
         let mut spending = vec![];
         let read_store = self.app.read_store();
-        let txid_prefixes = txids_by_script_hash(read_store, script_hash);
+        let txid_prefixes = txids_by_script_hash(read_store, script_hash)?;
+        let tx_rows = txrows_by_prefixes(read_store, txid_prefixes, max_height)?;
         // if the limit is enabled
-        if self.txid_limit > 0 && txid_prefixes.len() > self.txid_limit {
+        if self.txid_limit > 0 && tx_rows.len() > self.txid_limit {
             bail!(
                 "{}+ transactions found, query may take a long time",
-                txid_prefixes.len()
+                tx_rows.len()
             );
         }
-        for t in self.load_txns_by_prefix(read_store, txid_prefixes)? {
+        for t in self.load_txns(tx_rows)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         for funding_output in &funding {
-            if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
+            if let Some(spent) =
+                self.find_spending_input(read_store, &funding_output, max_height)?
+            {
                 spending.push(spent);
             }
         }
@@ -326,68 +657,307 @@ impl Query {
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
         let mut funding = vec![];
         let mut spending = vec![];
-        let txid_prefixes = txids_by_script_hash(tracker.index(), script_hash);
-        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes)? {
+        let txid_prefixes = txids_by_script_hash(tracker.index(), script_hash)?;
+        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes, None)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         // // TODO: dedup outputs (somehow) both confirmed and in mempool (e.g. reorg?)
         for funding_output in funding.iter().chain(confirmed_funding.iter()) {
-            if let Some(spent) = self.find_spending_input(tracker.index(), &funding_output)? {
+            if let Some(spent) = self.find_spending_input(tracker.index(), &funding_output, None)? {
                 spending.push(spent);
             }
         }
         Ok((funding, spending))
     }
 
-    pub fn status(&self, script_hash: &[u8]) -> Result<Status> {
-        let timer = self
+    /// Returns the position (within its block) of each confirmed txn, which shares its block
+    /// with other txns of the same history (using the index, or fetching the block's txids).
+    fn block_positions(
+        &self,
+        confirmed: &(Vec<FundingOutput>, Vec<SpendingInput>),
+    ) -> Result<HashMap<Txid, usize>> {
+        let mut positions = HashMap::new();
+        for (height, txids) in txids_by_height(confirmed, 0) {
+            if txids.len() < 2 {
+                continue; // no need to fetch the block
+            }
+            positions.extend(self.positions_in_block(height, &txids)?);
+        }
+        Ok(positions)
+    }
+
+    fn positions_in_block(
+        &self,
+        height: u32,
+        txids: &HashSet<Txid>,
+    ) -> Result<HashMap<Txid, usize>> {
+        if txids.len() < 2 {
+            return Ok(txids.iter().map(|txid| (*txid, 0)).collect()); // no need to fetch the block
+        }
+        let _timer = self
             .duration
-            .with_label_values(&["confirmed_status"])
+            .with_label_values(&["block_positions"])
             .start_timer();
-        let confirmed = self
-            .confirmed_status(script_hash)
-            .chain_err(|| "failed to get confirmed status")?;
-        timer.observe_duration();
+        // The positions are stored in the transaction rows (unless written by older versions)
+        let mut positions = HashMap::new();
+        for txid in txids {
+            let tx_row = txrow_by_txid(self.app.read_store(), txid)?;
+            match tx_row.and_then(|tx_row| tx_row.position) {
+                Some(position) => {
+                    positions.insert(*txid, position as usize);
+                }
+                None => break,
+            }
+        }
+        if positions.len() == txids.len() {
+            return Ok(positions);
+        }
+        let header = self
+            .app
+            .index()
+            .get_header(height as usize)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        let block_txids = self.app.daemon().getblocktxids(header.hash())?;
+        Ok(block_txids
+            .into_iter()
+            .enumerate()
+            .filter(|(_, txid)| txids.contains(txid))
+            .map(|(position, txid)| (txid, position))
+            .collect())
+    }
 
+    /// Returns the status of the mempool txns (funding `script_hash`, or spending its outputs).
+    fn unconfirmed_status(
+        &self,
+        script_hash: &[u8],
+        confirmed_funding: &[FundingOutput],
+    ) -> Result<Status> {
         let tracker = self.tracker.read().unwrap();
         let timer = self
             .duration
             .with_label_values(&["mempool_status"])
             .start_timer();
         let mempool = self
-            .mempool_status(script_hash, &confirmed.0, &tracker)
+            .mempool_status(script_hash, confirmed_funding, &tracker)
             .chain_err(|| "failed to get mempool status")?;
         timer.observe_duration();
 
         let mut txn_fees = HashMap::new();
+        let mut unconfirmed_parents = HashSet::new();
         let funding_txn_ids = mempool.0.iter().map(|funding| funding.txn_id);
         let spending_txn_ids = mempool.1.iter().map(|spending| spending.txn_id);
         for mempool_txid in funding_txn_ids.chain(spending_txn_ids) {
             tracker
                 .get_fee(&mempool_txid)
                 .map(|fee| txn_fees.insert(mempool_txid, fee));
+            if tracker.has_unconfirmed_inputs(&mempool_txid) {
+                unconfirmed_parents.insert(mempool_txid);
+            }
         }
 
         Ok(Status {
-            confirmed,
+            confirmed: (vec![], vec![]),
             mempool,
             txn_fees,
+            unconfirmed_parents,
+            block_positions: HashMap::new(),
         })
     }
 
+    pub fn status(&self, script_hash: &[u8]) -> Result<Status> {
+        let timer = self
+            .duration
+            .with_label_values(&["confirmed_status"])
+            .start_timer();
+        let confirmed = self
+            .confirmed_status(script_hash, None)
+            .chain_err(|| "failed to get confirmed status")?;
+        timer.observe_duration();
+        let block_positions = self.block_positions(&confirmed)?;
+
+        let mut status = self.unconfirmed_status(script_hash, &confirmed.0)?;
+        status.confirmed = confirmed;
+        status.block_positions = block_positions;
+        Ok(status)
+    }
+
+    /// Returns up to `limit` history items after `after_txid`, confirmed at `from_height` or above
+    /// (and up to `to_height`, if specified - otherwise followed by the mempool ones), together
+    /// with the total number of such items.
+    /// The candidate transactions are selected using the index, so only the transactions of the
+    /// blocks being paged are loaded (and `txid_limit` applies to each page).
+    /// Without the extended rows, the total may also count candidates which turn out to be
+    /// unrelated (e.g. spending another output of a funding transaction).
+    pub fn history_page(
+        &self,
+        script_hash: &[u8],
+        from_height: u32,
+        to_height: Option<u32>,
+        after_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Result<(Vec<HistoryItem>, usize)> {
+        let _timer = self
+            .duration
+            .with_label_values(&["history_page"])
+            .start_timer();
+        if self.app.index().row_format() == RowFormat::Extended {
+            let confirmed = self
+                .indexed_status(script_hash, to_height)
+                .chain_err(|| "failed to get confirmed status")?;
+            let mempool = match to_height {
+                Some(_) => vec![],
+                None => self
+                    .unconfirmed_status(script_hash, &confirmed.0)?
+                    .history(),
+            };
+            return history_page(
+                txids_by_height(&confirmed, from_height),
+                mempool,
+                after_txid,
+                limit,
+                |height, txids| self.positions_in_block(height, txids),
+            );
+        }
+        let read_store = self.app.read_store();
+        let txid_prefixes = txids_by_script_hash(read_store, script_hash)?;
+        let funding_heights: HashMap<Txid, u32> =
+            txrows_by_prefixes(read_store, txid_prefixes, to_height)?
+                .into_iter()
+                .map(|tx_row| (deserialize(&tx_row.key.txid).unwrap(), tx_row.height))
+                .collect();
+        // Spending txns are found by their funding txid (since the funding outputs are unknown)
+        let mut candidates = BTreeMap::<u32, HashSet<Txid>>::new();
+        let mut spending_rows = vec![];
+        for funding_txid in funding_heights.keys() {
+            let txid_prefixes = txids_by_funding_txn(read_store, funding_txid)?;
+            spending_rows.extend(txrows_by_prefixes(read_store, txid_prefixes, to_height)?);
+        }
+        let funding_txns = funding_heights
+            .iter()
+            .map(|(txid, height)| (*height, *txid));
+        let spending_txns = spending_rows
+            .into_iter()
+            .map(|tx_row| (tx_row.height, deserialize(&tx_row.key.txid).unwrap()));
+        for (height, txid) in funding_txns.chain(spending_txns) {
+            if height >= from_height {
+                candidates
+                    .entry(height)
+                    .or_insert_with(HashSet::new)
+                    .insert(txid);
+            }
+        }
+        let mempool = match to_height {
+            Some(_) => vec![],
+            None => {
+                let funding = self.funding_spent_by_mempool(script_hash, &funding_heights)?;
+                self.unconfirmed_status(script_hash, &funding)?.history()
+            }
+        };
+        let mut loaded = HashSet::new();
+        history_page(candidates, mempool, after_txid, limit, |height, txids| {
+            let mut verified = HashSet::new();
+            for txid in txids {
+                let txn = self.load_page_txn(&mut loaded, txid, height)?;
+                if self.is_related(&mut loaded, &txn, script_hash, &funding_heights)? {
+                    verified.insert(*txid);
+                }
+            }
+            self.positions_in_block(height, &verified)
+        })
+    }
+
+    // Loads a transaction of the current history page (which may load up to `txid_limit` txns)
+    fn load_page_txn(
+        &self,
+        loaded: &mut HashSet<Txid>,
+        txid: &Txid,
+        height: u32,
+    ) -> Result<Transaction> {
+        loaded.insert(*txid);
+        if self.txid_limit > 0 && loaded.len() > self.txid_limit {
+            bail!(
+                "{}+ transactions found, query may take a long time (try a smaller limit)",
+                loaded.len()
+            );
+        }
+        self.load_txn(txid, Some(height))
+    }
+
+    // Returns whether `txn` funds `script_hash`, or spends one of its (confirmed) outputs
+    fn is_related(
+        &self,
+        loaded: &mut HashSet<Txid>,
+        txn: &Transaction,
+        script_hash: &[u8],
+        funding_heights: &HashMap<Txid, u32>,
+    ) -> Result<bool> {
+        let pays_script_hash =
+            |output: &TxOut| compute_script_hash(&output.script_pubkey[..]) == script_hash;
+        if txn.output.iter().any(pays_script_hash) {
+            return Ok(true);
+        }
+        for input in &txn.input {
+            let prev = &input.previous_output;
+            if let Some(height) = funding_heights.get(&prev.txid) {
+                let funding_txn = self.load_page_txn(loaded, &prev.txid, *height)?;
+                if funding_txn
+                    .output
+                    .get(prev.vout as usize)
+                    .map_or(false, pays_script_hash)
+                {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // Returns the confirmed funding outputs (out of `funding_heights` txns) spent by mempool txns
+    fn funding_spent_by_mempool(
+        &self,
+        script_hash: &[u8],
+        funding_heights: &HashMap<Txid, u32>,
+    ) -> Result<Vec<FundingOutput>> {
+        let mut spent_txids = vec![];
+        {
+            let tracker = self.tracker.read().unwrap();
+            for txid in funding_heights.keys() {
+                if !txids_by_funding_txn(tracker.index(), txid)?.is_empty() {
+                    spent_txids.push(*txid);
+                }
+            }
+        }
+        let mut funding = vec![];
+        for txid in spent_txids {
+            let height = funding_heights[&txid];
+            let txn = self.load_txn(&txid, Some(height))?;
+            funding.extend(self.find_funding_outputs(&TxnHeight { txn, height }, script_hash));
+        }
+        Ok(funding)
+    }
+
     fn lookup_confirmed_blockhash(
         &self,
         tx_hash: &Txid,
         block_height: Option<u32>,
     ) -> Result<Option<BlockHash>> {
-        let blockhash = if self.tracker.read().unwrap().has_txn(&tx_hash) {
+        let header = self.lookup_confirmed_header(tx_hash, block_height)?;
+        Ok(header.map(|header| *header.hash()))
+    }
+
+    fn lookup_confirmed_header(
+        &self,
+        tx_hash: &Txid,
+        block_height: Option<u32>,
+    ) -> Result<Option<HeaderEntry>> {
+        let header = if self.tracker.read().unwrap().has_txn(&tx_hash) {
             None // found in mempool (as unconfirmed transaction)
         } else {
             // Lookup in confirmed transactions' index
             let height = match block_height {
                 Some(height) => height,
                 None => {
-                    txrow_by_txid(self.app.read_store(), &tx_hash)
+                    txrow_by_txid(self.app.read_store(), &tx_hash)?
                         .chain_err(|| format!("not indexed tx {}", tx_hash))?
                         .height
                 }
@@ -397,9 +967,9 @@ impl Query {
                 .index()
                 .get_header(height as usize)
                 .chain_err(|| format!("missing header at height {}", height))?;
-            Some(*header.hash())
+            Some(header)
         };
-        Ok(blockhash)
+        Ok(header)
     }
 
     // Internal API for transaction retrieval
@@ -407,6 +977,11 @@ impl Query {
         let _timer = self.duration.with_label_values(&["load_txn"]).start_timer();
         self.tx_cache.get_or_else(&txid, || {
             let blockhash = self.lookup_confirmed_blockhash(txid, block_height)?;
+            if let Some(blockhash) = blockhash {
+                if let Some(tx) = self.app.daemon().read_transaction(txid, &blockhash) {
+                    return Ok(serialize(&tx));
+                }
+            }
             let value: Value = self
                 .app
                 .daemon()
@@ -422,10 +997,19 @@ impl Query {
             .duration
             .with_label_values(&["get_transaction"])
             .start_timer();
-        let blockhash = self.lookup_confirmed_blockhash(tx_hash, /*block_height*/ None)?;
-        self.app
-            .daemon()
-            .gettransaction_raw(tx_hash, blockhash, verbose)
+        let header = self.lookup_confirmed_header(tx_hash, /*block_height*/ None)?;
+        let txn = self.load_txn(tx_hash, header.as_ref().map(|h| h.height() as u32))?;
+        if !verbose {
+            return Ok(json!(serialize(&txn).to_hex()));
+        }
+        let tip_height = self.get_best_header()?.height();
+        let network = self.app.daemon().network();
+        Ok(verbose_transaction(
+            &txn,
+            header.as_ref(),
+            tip_height,
+            network,
+        ))
     }
 
     pub fn get_confirmed_blockhash(&self, tx_hash: &Txid) -> Result<Value> {
@@ -539,12 +1123,54 @@ impl Query {
         self.app.daemon().broadcast(txn)
     }
 
+    pub fn save_mempool(&self, path: &Path) -> Result<()> {
+        self.tracker.read().unwrap().save(path)
+    }
+
+    pub fn load_mempool(&self, path: &Path) -> Result<usize> {
+        self.tracker.write().unwrap().load(path)
+    }
+
     pub fn update_mempool(&self) -> Result<()> {
         let _timer = self
             .duration
             .with_label_values(&["update_mempool"])
             .start_timer();
-        self.tracker.write().unwrap().update(self.app.daemon())
+        // bitcoind is queried without locking the tracker, so RPC requests are not blocked
+        let request = self.tracker.read().unwrap().update_request();
+        if let Some(update) = request.fetch(self.app.daemon())? {
+            self.tracker.write().unwrap().apply(update);
+        }
+        Ok(())
+    }
+
+    /// Adds a transaction (e.g. notified via ZMQ) to the mempool, without a full update.
+    /// Returns whether it was added (i.e. it is a new mempool transaction).
+    pub fn add_mempool_transaction(&self, tx: Transaction) -> bool {
+        let txid = tx.txid();
+        if self.tracker.read().unwrap().has_txn(&txid) {
+            return false;
+        }
+        // block transactions are notified too, but they are no longer in the mempool
+        if tx.is_coin_base() {
+            return false;
+        }
+        match txrow_by_txid(self.app.read_store(), &txid) {
+            Ok(None) => (),
+            Ok(Some(_)) => return false, // already indexed
+            Err(err) => {
+                warn!("failed to read {}: {}", txid, err);
+                return false;
+            }
+        }
+        let entry = match self.app.daemon().getmempoolentry(&txid) {
+            Ok(entry) => entry,
+            Err(err) => {
+                debug!("no mempool entry {}: {}", txid, err);
+                return false;
+            }
+        };
+        self.tracker.write().unwrap().add_notified(tx, entry)
     }
 
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
@@ -567,11 +1193,533 @@ impl Query {
         (last_fee_rate as f64) * 1e-5 // [BTC/kB] = 10^5 [sat/B]
     }
 
-    pub fn get_banner(&self) -> Result<String> {
-        self.app.get_banner()
+    pub fn mempool_size(&self) -> usize {
+        self.tracker.read().unwrap().count()
     }
 
     pub fn get_relayfee(&self) -> Result<f64> {
         self.app.daemon().get_relayfee()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::bitcoind::FakeBitcoind;
+    use crate::index::Index;
+    use crate::signal::Waiter;
+    use crate::store::DBStore;
+    use crate::util::HeaderList;
+    use bitcoin::blockdata::block::{Block, BlockHeader};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::transaction::TxIn;
+    use bitcoin::hashes::hex::FromHex;
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_inner([n; 32])
+    }
+
+    fn funding(n: u8, height: u32) -> FundingOutput {
+        FundingOutput {
+            txn_id: txid(n),
+            height,
+            output_index: 0,
+            value: 1000,
+        }
+    }
+
+    fn tx_hashes(items: &[HistoryItem]) -> Vec<Txid> {
+        items.iter().map(|item| item.tx_hash).collect()
+    }
+
+    #[test]
+    fn test_history_page() {
+        let confirmed = (
+            vec![
+                funding(3, 20),
+                funding(1, 10),
+                funding(2, 20),
+                funding(5, 30),
+                funding(6, 30), // an unrelated candidate
+            ],
+            vec![],
+        );
+        let mempool = || {
+            vec![HistoryItem {
+                height: 0,
+                tx_hash: txid(4),
+                fee: Some(100),
+            }]
+        };
+        let fetched = std::cell::RefCell::new(vec![]);
+        let page = |from_height, after_txid: Option<&Txid>, limit| {
+            fetched.borrow_mut().clear();
+            history_page(
+                txids_by_height(&confirmed, from_height),
+                mempool(),
+                after_txid,
+                limit,
+                |height, txids: &HashSet<Txid>| {
+                    fetched.borrow_mut().push(height);
+                    // txid(3) precedes txid(2) in its block
+                    Ok(txids
+                        .iter()
+                        .filter(|t| **t != txid(6))
+                        .map(|txid| (*txid, 3 - txid[0] as usize))
+                        .collect())
+                },
+            )
+        };
+
+        let (items, total) = page(0, None, 1).unwrap();
+        assert_eq!(total, 6);
+        assert_eq!(tx_hashes(&items), vec![txid(1)]);
+        assert_eq!(*fetched.borrow(), vec![10]); // stopped before reaching height 20
+
+        let (items, total) = page(0, Some(&txid(1)), 2).unwrap();
+        assert_eq!(total, 6);
+        assert_eq!(tx_hashes(&items), vec![txid(3), txid(2)]);
+        assert_eq!(*fetched.borrow(), vec![10, 20]);
+
+        let (items, total) = page(15, Some(&txid(2)), 10).unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(tx_hashes(&items), vec![txid(5), txid(4)]);
+        assert_eq!(items[1].fee, Some(100));
+        assert_eq!(*fetched.borrow(), vec![20, 30]);
+
+        let (items, _) = page(0, Some(&txid(5)), 10).unwrap();
+        assert_eq!(tx_hashes(&items), vec![txid(4)]);
+        let (items, _) = page(0, Some(&txid(4)), 10).unwrap();
+        assert!(items.is_empty());
+        assert!(fetched.borrow().is_empty()); // all the confirmed blocks are skipped
+        assert!(page(0, Some(&txid(7)), 10).is_err());
+    }
+
+    fn new_txn(inputs: &[OutPoint], scripts: &[u8]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|(txid, vout)| TxIn {
+                    previous_output: bitcoin::OutPoint::new(*txid, *vout as u32),
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: scripts
+                .iter()
+                .map(|opcode| TxOut {
+                    value: 10,
+                    script_pubkey: Script::from(vec![*opcode]),
+                })
+                .collect(),
+        }
+    }
+
+    fn coinbase(height: u8, opcode: u8) -> Transaction {
+        let mut txn = new_txn(&[(Txid::default(), 0xffff_ffff)], &[opcode]);
+        txn.input[0].script_sig = Script::from(vec![height]);
+        txn
+    }
+
+    fn new_block(prev: &Block, txdata: Vec<Transaction>) -> Block {
+        Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata,
+        }
+    }
+
+    fn temp_db_path(name: &str, format: RowFormat) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "electrs-query-{}-{:?}-{}",
+            name,
+            format,
+            std::process::id()
+        ))
+    }
+
+    // Indexes `bitcoind`'s chain into a new DB at `path`
+    fn indexed_query(
+        bitcoind: &FakeBitcoind,
+        path: &Path,
+        format: RowFormat,
+        txid_limit: usize,
+    ) -> Arc<Query> {
+        DBStore::destroy(path);
+        let metrics = Metrics::new(([127, 0, 0, 1], 0).into());
+        let signal = Waiter::start();
+        let daemon = bitcoind.daemon(&signal, &metrics);
+        let store = DBStore::open(path, false, format).unwrap();
+        let index = Index::load(&store, &daemon, &metrics, 10, format).unwrap();
+        index.update(&store, &signal).unwrap();
+        let app = App::new(store, index, daemon).unwrap();
+        let tx_cache = TransactionCache::new(1 << 20, &metrics);
+        Query::new(app, &metrics, tx_cache, txid_limit)
+    }
+
+    #[test]
+    fn test_history_above_txid_limit() {
+        let (s, o) = (0x51, 0x52); // the queried script, and an unrelated one
+        let c1 = coinbase(1, s);
+        let c2 = coinbase(2, s);
+        let a = new_txn(&[(c1.txid(), 0)], &[o, s]);
+        let b = new_txn(&[(a.txid(), 1)], &[o]);
+        let e = new_txn(&[(a.txid(), 0)], &[o]); // spends another output of `a`
+        let c4 = coinbase(4, s);
+        let g = new_txn(&[(c4.txid(), 0)], &[o]);
+        let (c5, c6, c7) = (coinbase(5, s), coinbase(6, s), coinbase(7, s));
+        let expected: Vec<Txid> = [&c1, &c2, &a, &b, &c4, &g, &c5, &c6, &c7]
+            .iter()
+            .map(|txn| txn.txid())
+            .collect();
+
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        let txdata = vec![
+            vec![c1],
+            vec![c2, a],
+            vec![coinbase(3, o), b, e],
+            vec![c4, g],
+            vec![c5],
+            vec![c6],
+            vec![c7],
+        ];
+        for txns in txdata {
+            let block = new_block(blocks.last().unwrap(), txns);
+            blocks.push(block);
+        }
+        let bitcoind = FakeBitcoind::start(blocks);
+        let script_hash = compute_script_hash(&[s]);
+
+        for &format in &[RowFormat::Compact, RowFormat::Extended] {
+            let path = temp_db_path("history", format);
+            let query = indexed_query(&bitcoind, &path, format, /*txid_limit=*/ 5);
+
+            // `e` is a candidate only when using the compact rows, since its input is not checked
+            let total = match format {
+                RowFormat::Compact => expected.len() + 1,
+                RowFormat::Extended => expected.len(),
+            };
+            let mut history = vec![];
+            loop {
+                let (page, page_total) = query
+                    .history_page(&script_hash, 0, None, history.last(), 1)
+                    .unwrap();
+                assert_eq!(page_total, total);
+                if page.is_empty() {
+                    break;
+                }
+                history.extend(tx_hashes(&page));
+            }
+            assert_eq!(history, expected);
+
+            let (page, _) = query
+                .history_page(&script_hash, 2, Some(3), None, 10)
+                .unwrap();
+            assert_eq!(tx_hashes(&page), expected[1..4].to_vec());
+            if format == RowFormat::Compact {
+                // more than `txid_limit` transactions would be loaded
+                assert!(query.status(&script_hash).is_err());
+                assert!(query.history_page(&script_hash, 0, None, None, 10).is_err());
+            } else {
+                let status = query.status(&script_hash).unwrap();
+                assert_eq!(tx_hashes(&status.history()), expected);
+            }
+            DBStore::destroy(&path);
+        }
+    }
+
+    #[test]
+    fn test_unconfirmed_history() {
+        let status = Status {
+            confirmed: (vec![funding(3, 20)], vec![]),
+            mempool: (vec![funding(1, 0), funding(2, 0), funding(4, 0)], vec![]),
+            txn_fees: HashMap::new(),
+            unconfirmed_parents: vec![txid(1)].into_iter().collect(),
+            block_positions: HashMap::new(),
+        };
+        let history = status.history();
+        assert_eq!(
+            tx_hashes(&history),
+            vec![txid(3), txid(2), txid(4), txid(1)]
+        );
+        let heights: Vec<i32> = history.iter().map(|item| item.height).collect();
+        assert_eq!(heights, vec![20, 0, 0, -1]);
+    }
+
+    // Real (mainnet) txids, so that a byte order mismatch between the txid's hex and its bytes
+    // changes the status hash.
+    const TXIDS: [&str; 6] = [
+        "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b", // block 0 coinbase
+        "0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098", // block 1 coinbase
+        "9b0fc92260312ce44e74ef369f5c66bbb85848f2eddd5a7a1cde251e54ccfdd5", // block 2 coinbase
+        "0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9", // block 9 coinbase
+        "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082", // block 170 coinbase
+        "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16", // block 170, position 1
+    ];
+
+    fn real_txid(n: u8) -> Txid {
+        Txid::from_hex(TXIDS[n as usize]).unwrap()
+    }
+
+    // The expected status hashes follow the protocol's definition (independently of `Status`),
+    // e.g. `printf '0e3e2357...d512098:1:' | sha256sum` for the 2nd case.
+    #[test]
+    fn test_status_hash_conformance() {
+        let fund = |n, height| FundingOutput {
+            txn_id: real_txid(n),
+            height,
+            output_index: 0,
+            value: 1000,
+        };
+        struct Case {
+            confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
+            mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
+            unconfirmed_parents: Vec<u8>,
+            block_positions: Vec<(u8, usize)>,
+            expected_status: &'static str, // "<txid>:<height>:" items, using `TXIDS` indices
+            expected_hash: Option<&'static str>,
+        }
+        let cases = vec![
+            Case {
+                confirmed: (vec![], vec![]),
+                mempool: (vec![], vec![]),
+                unconfirmed_parents: vec![],
+                block_positions: vec![],
+                expected_status: "",
+                expected_hash: None,
+            },
+            Case {
+                confirmed: (vec![fund(1, 1)], vec![]),
+                mempool: (vec![], vec![]),
+                unconfirmed_parents: vec![],
+                block_positions: vec![],
+                expected_status: "1:1:",
+                expected_hash: Some(
+                    "2cf956233db9efea5fdbbf4192426a792dff2e472334ddd8691829d78e6ad318",
+                ),
+            },
+            // txns in the same block are ordered by their position (not by txid)
+            Case {
+                confirmed: (vec![fund(5, 170), fund(4, 170), fund(3, 9)], vec![]),
+                mempool: (vec![], vec![]),
+                unconfirmed_parents: vec![],
+                block_positions: vec![(4, 0), (5, 1)],
+                expected_status: "3:9:4:170:5:170:",
+                expected_hash: Some(
+                    "72d374093424a061ce843a1067f88870e6035890db725cdcf62bf13b37e126f4",
+                ),
+            },
+            // mempool txns follow the confirmed ones, with unconfirmed parents (-1) last
+            Case {
+                confirmed: (vec![fund(3, 9)], vec![]),
+                mempool: (vec![fund(2, 0), fund(1, 0), fund(0, 0)], vec![]),
+                unconfirmed_parents: vec![1],
+                block_positions: vec![],
+                expected_status: "3:9:0:0:2:0:1:-1:",
+                expected_hash: Some(
+                    "9837ca24404437f2c07aefff2487c561365f0bac26d40092552b49ca02084cb0",
+                ),
+            },
+            // a txn is listed once, even if it both funds and spends
+            Case {
+                confirmed: (
+                    vec![fund(1, 1), fund(5, 170)],
+                    vec![SpendingInput {
+                        txn_id: real_txid(5),
+                        height: 170,
+                        funding_output: (real_txid(1), 0),
+                        value: 1000,
+                    }],
+                ),
+                mempool: (vec![], vec![]),
+                unconfirmed_parents: vec![],
+                block_positions: vec![],
+                expected_status: "1:1:5:170:",
+                expected_hash: Some(
+                    "b987719249b7cf66aac8eb82a08b1d52dccd5f52cf252542541d561cc23d9035",
+                ),
+            },
+        ];
+        for case in cases {
+            let status = Status {
+                confirmed: case.confirmed,
+                mempool: case.mempool,
+                txn_fees: HashMap::new(),
+                unconfirmed_parents: case
+                    .unconfirmed_parents
+                    .into_iter()
+                    .map(real_txid)
+                    .collect(),
+                block_positions: case
+                    .block_positions
+                    .into_iter()
+                    .map(|(n, position)| (real_txid(n), position))
+                    .collect(),
+            };
+            let expected_status: String = case
+                .expected_status
+                .split_terminator(':')
+                .collect::<Vec<&str>>()
+                .chunks(2)
+                .map(|item| format!("{}:{}:", TXIDS[item[0].parse::<usize>().unwrap()], item[1]))
+                .collect();
+            let status_str: String = status
+                .history()
+                .iter()
+                .map(|item| format!("{}:{}:", item.tx_hash, item.height))
+                .collect();
+            assert_eq!(status_str, expected_status);
+            assert_eq!(
+                status.hash().map(hex::encode),
+                case.expected_hash.map(String::from)
+            );
+        }
+    }
+
+    // Raw mainnet transactions: block 9's coinbase pays Satoshi's key, which is spent by the
+    // first non-coinbase transaction (at block 170), paying Hal's key and returning change.
+    const BLOCK_9_COINBASE: &str = concat!(
+        "01000000010000000000000000000000000000000000000000000000000000000000000000ffffff",
+        "ff0704ffff001d0134ffffffff0100f2052a0100000043410411db93e1dcdb8a016b49840f8c53bc",
+        "1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f",
+        "999b8643f656b412a3ac00000000",
+    );
+    const BLOCK_170_TXN: &str = concat!(
+        "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000",
+        "004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd4102",
+        "20181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200",
+        "ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa2",
+        "8414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee00",
+        "00000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0",
+        "eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000",
+    );
+
+    // Indexes the real transactions at their mainnet heights and positions, so that the status
+    // is computed from the rows (and not from synthetic `Status` values).
+    #[test]
+    fn test_real_history_status() {
+        let decode =
+            |hex: &str| -> Transaction { deserialize(&Vec::<u8>::from_hex(hex).unwrap()).unwrap() };
+        let coinbase_9 = decode(BLOCK_9_COINBASE);
+        let txn_170 = decode(BLOCK_170_TXN);
+        assert_eq!(coinbase_9.txid(), real_txid(3));
+        assert_eq!(txn_170.txid(), real_txid(5));
+        let satoshi = compute_script_hash(&coinbase_9.output[0].script_pubkey[..]);
+        let hal = compute_script_hash(&txn_170.output[0].script_pubkey[..]);
+
+        let mut blocks = vec![genesis_block(Network::Regtest)];
+        for height in 1..=170 {
+            let txdata = match height {
+                9 => vec![coinbase_9.clone()],
+                170 => vec![coinbase(height, 0x51), txn_170.clone()],
+                _ => vec![coinbase(height, 0x51)],
+            };
+            let block = new_block(blocks.last().unwrap(), txdata);
+            blocks.push(block);
+        }
+        let bitcoind = FakeBitcoind::start(blocks);
+
+        // `printf '<txid>:9:<txid>:170:' | sha256sum`, using the txids above
+        let cases = [
+            (
+                &satoshi,
+                "3:9:5:170:",
+                "a36dc37cafd538cd07cedbd3eaa83adb8f438dacfb27c267225fb8bf958c2ee5",
+            ),
+            (
+                &hal,
+                "5:170:",
+                "de05815d073f47cd1383d961d1cb0381ca4dff1af4cb1a42bead4f47fd1345e2",
+            ),
+        ];
+        for &format in &[RowFormat::Compact, RowFormat::Extended] {
+            let path = temp_db_path("real", format);
+            let query = indexed_query(&bitcoind, &path, format, /*txid_limit=*/ 100);
+            for (script_hash, expected_status, expected_hash) in cases.iter() {
+                let status = query.status(&script_hash[..]).unwrap();
+                let status_str: String = status
+                    .history()
+                    .iter()
+                    .map(|item| format!("{}:{}:", item.tx_hash, item.height))
+                    .collect();
+                let expected_status: String = expected_status
+                    .split_terminator(':')
+                    .collect::<Vec<&str>>()
+                    .chunks(2)
+                    .map(|item| {
+                        format!("{}:{}:", TXIDS[item[0].parse::<usize>().unwrap()], item[1])
+                    })
+                    .collect();
+                assert_eq!(status_str, expected_status);
+                assert_eq!(status.hash().map(hex::encode).unwrap(), *expected_hash);
+            }
+            DBStore::destroy(&path);
+        }
+    }
+
+    #[test]
+    fn test_add_mempool_transaction() {
+        let c1 = coinbase(1, 0x51);
+        let a = new_txn(&[(c1.txid(), 0)], &[0x51]);
+        let m = new_txn(&[(a.txid(), 0)], &[0x51]);
+        let genesis = genesis_block(Network::Regtest);
+        let block = new_block(&genesis, vec![c1.clone(), a.clone()]);
+        let bitcoind = FakeBitcoind::start(vec![genesis, block]);
+        bitcoind.set_mempool(&[m.txid()]);
+
+        let path = temp_db_path("mempool", RowFormat::Compact);
+        let query = indexed_query(
+            &bitcoind,
+            &path,
+            RowFormat::Compact,
+            /*txid_limit=*/ 10,
+        );
+        // the block's transactions are notified too, without being in the mempool
+        assert!(!query.add_mempool_transaction(c1));
+        assert!(!query.add_mempool_transaction(a));
+        assert_eq!(bitcoind.requests("getmempoolentry"), 0);
+
+        assert!(query.add_mempool_transaction(m.clone()));
+        assert!(!query.add_mempool_transaction(m));
+        assert_eq!(bitcoind.requests("getmempoolentry"), 1);
+        assert_eq!(query.mempool_size(), 1);
+        DBStore::destroy(&path);
+    }
+
+    #[test]
+    fn test_verbose_transaction() {
+        let block = bitcoin::blockdata::constants::genesis_block(Network::Bitcoin);
+        let txn = &block.txdata[0];
+        let header = HeaderList::empty().order(vec![block.header]).remove(0);
+
+        let value = verbose_transaction(txn, Some(&header), 9, Network::Bitcoin);
+        assert_eq!(
+            value["txid"],
+            "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b"
+        );
+        assert_eq!(value["hash"], value["txid"]);
+        assert_eq!(value["size"], 204);
+        assert_eq!(value["vsize"], 204);
+        assert!(value["vin"][0]["coinbase"].is_string());
+        assert_eq!(value["vin"][0]["sequence"], 0xffff_ffffu32);
+        assert_eq!(value["vout"][0]["value"], 50.0);
+        assert_eq!(value["vout"][0]["scriptPubKey"]["type"], "pubkey");
+        assert_eq!(value["blockhash"], json!(block.block_hash()));
+        assert_eq!(value["confirmations"], 10);
+        assert_eq!(value["time"], 1_231_006_505);
+
+        let value = verbose_transaction(txn, None, 9, Network::Bitcoin);
+        assert_eq!(value["confirmations"], 0);
+        assert!(value.get("blockhash").is_none());
+        assert_eq!(value["hex"], serialize(txn).to_hex());
+    }
+}
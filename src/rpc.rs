@@ -1,23 +1,49 @@
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::hash_types::BlockHash;
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::{sha256d::Hash as Sha256dHash, Hash};
 use error_chain::ChainedError;
 use serde_json::{from_str, Value};
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
 use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 
+use crate::app::Banner;
+use crate::config::{ElectrumTls, PublicHost};
+use crate::daemon::Daemon;
 use crate::errors::*;
+use crate::index::SyncProgress;
 use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::peers::{PeerManager, MAX_ADDED_PEERS};
 use crate::query::{Query, Status};
+#[cfg(feature = "tls")]
+use crate::tls;
 use crate::util::{spawn_thread, Channel, HeaderEntry};
+use crate::websocket::WebSocket;
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
-const PROTOCOL_VERSION: &str = "1.4";
+const TIP_HEADER_MAX_AGE: Duration = Duration::from_secs(10); // during initial sync
+const MAX_HISTORY_PAGE_SIZE: usize = 1000;
+
+// Sorted by ascending version (1.4.1 only affects AuxPoW headers, so it's identical to 1.4)
+const PROTOCOL_VERSIONS: [ProtocolVersion; 4] = [
+    ProtocolVersion::new(1, 4, 0),
+    ProtocolVersion::new(1, 4, 1),
+    ProtocolVersion::new(1, 4, 2),
+    ProtocolVersion::new(1, 5, 0),
+];
+const MIN_PROTOCOL_VERSION: ProtocolVersion = PROTOCOL_VERSIONS[0];
+const MAX_PROTOCOL_VERSION: ProtocolVersion = PROTOCOL_VERSIONS[3];
+
+// Limits the time a client may take to complete the WebSocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 // JSON-RPC spec errors
 const PARSE_ERROR: i16 = -32700;
@@ -27,6 +53,77 @@ const INVALID_REQUEST: i16 = -32600;
 // electrum-specific errors
 const BAD_REQUEST: i16 = 1;
 const DAEMON_ERROR: i16 = 2;
+const SYNCING_ERROR: i16 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ProtocolVersion {
+    major: u32,
+    minor: u32,
+    revision: u32,
+}
+
+impl ProtocolVersion {
+    const fn new(major: u32, minor: u32, revision: u32) -> Self {
+        ProtocolVersion {
+            major,
+            minor,
+            revision,
+        }
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split('.')
+            .map(u32::from_str)
+            .collect::<std::result::Result<Vec<u32>, _>>()
+            .chain_err(|| format!("invalid protocol version: {:?}", s))?;
+        match parts.as_slice() {
+            [major, minor] => Ok(ProtocolVersion::new(*major, *minor, 0)),
+            [major, minor, revision] => Ok(ProtocolVersion::new(*major, *minor, *revision)),
+            _ => bail!("invalid protocol version: {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)?;
+        if self.revision > 0 {
+            write!(f, ".{}", self.revision)?;
+        }
+        Ok(())
+    }
+}
+
+fn protocol_version_from_value(val: &Value) -> Result<ProtocolVersion> {
+    val.as_str()
+        .chain_err(|| format!("non-string protocol version: {:?}", val))?
+        .parse()
+}
+
+/// Returns the highest supported protocol version within the client's [min, max] range.
+fn negotiate_protocol_version(
+    min: ProtocolVersion,
+    max: ProtocolVersion,
+) -> Option<ProtocolVersion> {
+    PROTOCOL_VERSIONS
+        .iter()
+        .rev()
+        .find(|version| min <= **version && **version <= max)
+        .cloned()
+}
+
+/// Returns the protocol version that introduced `method` (if it's newer than the minimal one).
+fn method_protocol_version(method: &str) -> Option<ProtocolVersion> {
+    match method {
+        "blockchain.scripthash.unsubscribe" => Some(ProtocolVersion::new(1, 4, 2)),
+        _ => None,
+    }
+}
 
 // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
 fn hash_from_value<T: Hash>(val: Option<&Value>) -> Result<T> {
@@ -62,6 +159,24 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
     bool_from_value(val, name)
 }
 
+/// Returns this server's `server.features` result.
+pub fn server_features(genesis_hash: &BlockHash, public_hosts: &[PublicHost]) -> Value {
+    let mut hosts = Map::new();
+    for host in public_hosts {
+        let ports = json!({"tcp_port": host.tcp_port, "ssl_port": host.ssl_port});
+        hosts.insert(host.hostname.clone(), ports);
+    }
+    json!({
+        "genesis_hash": genesis_hash.to_hex(),
+        "hosts": hosts,
+        "protocol_min": MIN_PROTOCOL_VERSION.to_string(),
+        "protocol_max": MAX_PROTOCOL_VERSION.to_string(),
+        "server_version": format!("electrs {}", ELECTRS_VERSION),
+        "hash_function": "sha256",
+        "pruning": Value::Null,
+    })
+}
+
 fn unspent_from_status(status: &Status) -> Value {
     json!(Value::Array(
         status
@@ -78,6 +193,7 @@ fn unspent_from_status(status: &Status) -> Value {
 }
 
 fn json_rpc_error_from_error(error: &Error) -> Value {
+    let mut message = error.to_string();
     let code = {
         let mut error: &dyn std::error::Error = error;
         loop {
@@ -87,6 +203,10 @@ fn json_rpc_error_from_error(error: &Error) -> Value {
                     ErrorKind::InvalidRequest(_) => break INVALID_REQUEST,
                     ErrorKind::ParseError => break PARSE_ERROR,
                     ErrorKind::Daemon(_, _) => break DAEMON_ERROR,
+                    ErrorKind::Syncing(_, _) => {
+                        message = e.to_string(); // should not be hidden by the chained errors
+                        break SYNCING_ERROR;
+                    }
                     _ => (),
                 }
             }
@@ -98,92 +218,312 @@ fn json_rpc_error_from_error(error: &Error) -> Value {
     };
     json!({
         "code": code,
-        "message": error.to_string(),
+        "message": message,
     })
 }
 
+fn error_reply(id: Value, error: &Error) -> Value {
+    let error = json_rpc_error_from_error(error);
+    json!({"jsonrpc": "2.0", "id": id, "error": error})
+}
+
 type Map = serde_json::Map<String, Value>;
 
+/// Electrum client connection (over plain TCP, TLS or WebSocket).
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(tls::TlsStream),
+    WebSocket(Box<WebSocket>),
+}
+
+impl ClientStream {
+    fn try_clone(&self) -> io::Result<ClientStream> {
+        match self {
+            ClientStream::Tcp(stream) => stream.try_clone().map(ClientStream::Tcp),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.try_clone().map(ClientStream::Tls),
+            ClientStream::WebSocket(stream) => stream
+                .try_clone()
+                .map(|stream| ClientStream::WebSocket(Box::new(stream))),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.shutdown(how),
+            ClientStream::WebSocket(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.read(buf),
+            ClientStream::WebSocket(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.write(buf),
+            ClientStream::WebSocket(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => stream.flush(),
+            ClientStream::WebSocket(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Serves the queries using the index, after the initial sync is over.
+/// Until then, block headers are fetched from bitcoind, the `server.*` methods are served
+/// and the other queries fail.
+pub struct Backend {
+    query: RwLock<Option<Arc<Query>>>,
+    daemon: Daemon,
+    progress: Arc<SyncProgress>,
+    peers: Arc<PeerManager>,
+    banner: Banner,
+}
+
+impl Backend {
+    pub fn new(
+        daemon: Daemon,
+        progress: Arc<SyncProgress>,
+        peers: Arc<PeerManager>,
+        banner: Banner,
+    ) -> Arc<Backend> {
+        Arc::new(Backend {
+            query: RwLock::new(None),
+            daemon,
+            progress,
+            peers,
+            banner,
+        })
+    }
+
+    /// Starts serving all queries (should be called when the initial sync is over).
+    pub fn set_query(&self, query: Arc<Query>) {
+        *self.query.write().unwrap() = Some(query);
+        self.progress.finish();
+    }
+
+    fn query(&self) -> Result<Arc<Query>> {
+        match self.query.read().unwrap().clone() {
+            Some(query) => Ok(query),
+            None => {
+                let (height, tip) = self.progress.get();
+                bail!(ErrorKind::Syncing(height, tip))
+            }
+        }
+    }
+
+    fn get_headers(&self, heights: &[usize]) -> Result<Vec<HeaderEntry>> {
+        if let Some(query) = self.query.read().unwrap().clone() {
+            return Ok(query.get_headers(heights));
+        }
+        let tip = self.daemon.get_tip_height()?;
+        let heights: Vec<usize> = heights.iter().cloned().filter(|h| *h <= tip).collect();
+        if heights.is_empty() {
+            return Ok(vec![]);
+        }
+        let headers = self.daemon.getblockheaders(&heights)?;
+        Ok(heights
+            .into_iter()
+            .zip(headers)
+            .map(|(height, header)| HeaderEntry::new(height, header))
+            .collect())
+    }
+
+    fn get_best_header(&self) -> Result<HeaderEntry> {
+        if let Some(query) = self.query.read().unwrap().clone() {
+            return query.get_best_header();
+        }
+        // bitcoind's best header is cached, instead of being fetched for each client
+        if let Some(header) = self.progress.tip_header(TIP_HEADER_MAX_AGE) {
+            return Ok(header);
+        }
+        let tip = self.daemon.get_tip_height()?;
+        let header = self
+            .get_headers(&[tip])?
+            .pop()
+            .chain_err(|| format!("missing header at height {}", tip))?;
+        self.progress.set_tip_header(header.clone());
+        Ok(header)
+    }
+
+    /// Returns the banner, replacing `$SERVER_VERSION`, `$DAEMON_SUBVERSION`, `$TIP_HEIGHT`,
+    /// `$MEMPOOL_SIZE` and `$DONATION_ADDRESS` by their current values.
+    fn get_banner(&self) -> Result<String> {
+        let mut banner = self.banner.template();
+        if banner.contains("$DAEMON_SUBVERSION") {
+            let subversion = self.daemon.get_subversion()?;
+            banner = banner.replace("$DAEMON_SUBVERSION", &subversion);
+        }
+        let tip_height = self.get_best_header()?.height();
+        // the mempool is tracked only after the initial sync is over
+        let mempool_size = self
+            .query
+            .read()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |query| query.mempool_size());
+        let donation_address = self.banner.donation_address().unwrap_or("");
+        Ok(banner
+            .replace("$SERVER_VERSION", env!("CARGO_PKG_VERSION"))
+            .replace("$TIP_HEIGHT", &tip_height.to_string())
+            .replace("$MEMPOOL_SIZE", &mempool_size.to_string())
+            .replace("$DONATION_ADDRESS", donation_address))
+    }
+
+    /// Re-reads the banner file (if configured).
+    pub fn reload_banner(&self) -> Result<()> {
+        self.banner.reload()
+    }
+}
+
 struct Connection {
-    query: Arc<Query>,
+    backend: Arc<Backend>,
+    protocol_version: Option<ProtocolVersion>, // set by `server.version`
     last_header_entry: Option<HeaderEntry>,
     status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
-    stream: TcpStream,
+    stream: ClientStream,
     addr: SocketAddr,
+    added_peers: usize, // via `server.add_peer`
     sender: SyncSender<Message>,
     stats: Arc<Stats>,
     relayfee: f64,
+    max_batch_size: usize,
 }
 
 impl Connection {
     pub fn new(
-        query: Arc<Query>,
-        stream: TcpStream,
+        backend: Arc<Backend>,
+        stream: ClientStream,
         addr: SocketAddr,
         stats: Arc<Stats>,
         relayfee: f64,
+        max_batch_size: usize,
         sender: SyncSender<Message>,
     ) -> Connection {
         Connection {
-            query,
+            backend,
+            protocol_version: None,
             last_header_entry: None, // disable header subscription for now
             status_hashes: HashMap::new(),
             stream,
             addr,
+            added_peers: 0,
             sender,
             stats,
             relayfee,
+            max_batch_size,
         }
     }
 
+    fn query(&self) -> Result<Arc<Query>> {
+        self.backend.query()
+    }
+
     fn blockchain_headers_subscribe(&mut self) -> Result<Value> {
-        let entry = self.query.get_best_header()?;
+        let entry = self.backend.get_best_header()?;
         let hex_header = hex::encode(serialize(entry.header()));
         let result = json!({"hex": hex_header, "height": entry.height()});
         self.last_header_entry = Some(entry);
         Ok(result)
     }
 
-    fn server_version(&self, params: &[Value]) -> Result<Value> {
-        if params.len() != 2 {
+    // Clients that don't negotiate a version are served using the oldest one
+    fn protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version.unwrap_or(MIN_PROTOCOL_VERSION)
+    }
+
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
+        if params.len() > 2 {
             bail!("invalid params: {:?}", params);
         }
-        let client_id = params[0]
-            .as_str()
-            .chain_err(|| format!("invalid client_id: {:?}", params[0]))?;
-        // TODO: support (min, max) protocol version limits
-        let client_version = params[1]
-            .as_str()
-            .chain_err(|| format!("invalid client_version: {:?}", params[1]))?;
-
-        if client_version != PROTOCOL_VERSION {
-            bail!(
-                "{} requested protocol version {}, server supports {}",
-                client_id,
-                client_version,
-                PROTOCOL_VERSION
-            );
+        if let Some(version) = self.protocol_version {
+            bail!("protocol version {} is already negotiated", version);
         }
+        let client_id = match params.get(0) {
+            Some(value) => value
+                .as_str()
+                .chain_err(|| format!("invalid client_id: {:?}", value))?,
+            None => "",
+        };
+        // The client may send either a single version, or a [min, max] range
+        let (min, max) = match params.get(1) {
+            None => (MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION),
+            Some(Value::Array(range)) if range.len() == 2 => (
+                protocol_version_from_value(&range[0])?,
+                protocol_version_from_value(&range[1])?,
+            ),
+            Some(value) => {
+                let version = protocol_version_from_value(value)?;
+                (version, version)
+            }
+        };
+        let version = negotiate_protocol_version(min, max).chain_err(|| {
+            format!(
+                "{} requested protocol versions {}-{}, server supports {}-{}",
+                client_id, min, max, MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION
+            )
+        })?;
+        debug!(
+            "[{}] {} negotiated protocol version {}",
+            self.addr, client_id, version
+        );
+        self.protocol_version = Some(version);
         Ok(json!([
             format!("electrs {}", ELECTRS_VERSION),
-            PROTOCOL_VERSION
+            version.to_string()
         ]))
     }
 
     fn server_banner(&self) -> Result<Value> {
-        Ok(json!(self.query.get_banner()?))
+        Ok(json!(self.backend.get_banner()?))
     }
 
     fn server_donation_address(&self) -> Result<Value> {
-        Ok(Value::Null)
+        Ok(json!(self.backend.banner.donation_address()))
+    }
+
+    fn server_features(&self) -> Result<Value> {
+        Ok(self.backend.peers.features().clone())
     }
 
     fn server_peers_subscribe(&self) -> Result<Value> {
-        Ok(json!([]))
+        Ok(self.backend.peers.subscribe())
+    }
+
+    fn server_add_peer(&mut self, params: &[Value]) -> Result<Value> {
+        let features = params.get(0).chain_err(|| "missing features")?;
+        let limit = MAX_ADDED_PEERS.saturating_sub(self.added_peers);
+        let added = self
+            .backend
+            .peers
+            .add_features(features, &self.addr, limit)?;
+        self.added_peers += added;
+        Ok(json!(added > 0))
     }
 
     fn mempool_get_fee_histogram(&self) -> Result<Value> {
-        Ok(json!(self.query.get_fee_histogram()))
+        Ok(json!(self.query()?.get_fee_histogram()))
     }
 
     fn blockchain_block_header(&self, params: &[Value]) -> Result<Value> {
@@ -191,8 +531,8 @@ impl Connection {
         let cp_height = usize_from_value_or(params.get(1), "cp_height", 0)?;
 
         let raw_header_hex: String = self
-            .query
-            .get_headers(&[height])
+            .backend
+            .get_headers(&[height])?
             .into_iter()
             .map(|entry| hex::encode(&serialize(entry.header())))
             .collect();
@@ -200,7 +540,7 @@ impl Connection {
         if cp_height == 0 {
             return Ok(json!(raw_header_hex));
         }
-        let (branch, root) = self.query.get_header_merkle_proof(height, cp_height)?;
+        let (branch, root) = self.query()?.get_header_merkle_proof(height, cp_height)?;
 
         let branch_vec: Vec<String> = branch.into_iter().map(|b| b.to_hex()).collect();
 
@@ -217,38 +557,42 @@ impl Connection {
         let cp_height = usize_from_value_or(params.get(2), "cp_height", 0)?;
         let heights: Vec<usize> = (start_height..(start_height + count)).collect();
         let headers: Vec<String> = self
-            .query
-            .get_headers(&heights)
+            .backend
+            .get_headers(&heights)?
             .into_iter()
             .map(|entry| hex::encode(&serialize(entry.header())))
             .collect();
 
+        let mut result = json!({
+            "count": headers.len(),
+            "max": 2016,
+        });
+        let result_obj = result.as_object_mut().unwrap();
+        // Since protocol 1.5, the headers are returned as a list (instead of a concatenated string)
+        if self.protocol_version() >= ProtocolVersion::new(1, 5, 0) {
+            result_obj.insert("headers".to_owned(), json!(headers));
+        } else {
+            result_obj.insert("hex".to_owned(), json!(headers.join("")));
+        }
+
         if count == 0 || cp_height == 0 {
-            return Ok(json!({
-                "count": headers.len(),
-                "hex": headers.join(""),
-                "max": 2016,
-            }));
+            return Ok(result);
         }
 
         let (branch, root) = self
-            .query
+            .query()?
             .get_header_merkle_proof(start_height + (count - 1), cp_height)?;
 
         let branch_vec: Vec<String> = branch.into_iter().map(|b| b.to_hex()).collect();
 
-        Ok(json!({
-            "count": headers.len(),
-            "hex": headers.join(""),
-            "max": 2016,
-            "root": root.to_hex(),
-            "branch" : branch_vec
-        }))
+        result_obj.insert("root".to_owned(), json!(root.to_hex()));
+        result_obj.insert("branch".to_owned(), json!(branch_vec));
+        Ok(result)
     }
 
     fn blockchain_estimatefee(&self, params: &[Value]) -> Result<Value> {
         let blocks_count = usize_from_value(params.get(0), "blocks_count")?;
-        let fee_rate = self.query.estimate_fee(blocks_count); // in BTC/kB
+        let fee_rate = self.query()?.estimate_fee(blocks_count); // in BTC/kB
         Ok(json!(fee_rate.max(self.relayfee)))
     }
 
@@ -259,7 +603,7 @@ impl Connection {
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        let status = self.query()?.status(&script_hash[..])?;
         let result = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
         if self
             .status_hashes
@@ -272,10 +616,20 @@ impl Connection {
         Ok(result)
     }
 
+    fn blockchain_scripthash_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
+        let subscribed = self.status_hashes.remove(&script_hash).is_some();
+        if subscribed {
+            self.stats.subscriptions.dec();
+        }
+        Ok(json!(subscribed))
+    }
+
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        let status = self.query()?.status(&script_hash[..])?;
         Ok(
             json!({ "confirmed": status.confirmed_balance(), "unconfirmed": status.mempool_balance() }),
         )
@@ -284,7 +638,10 @@ impl Connection {
     fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        if params.len() > 1 {
+            return self.scripthash_get_history_page(&script_hash, &params[1..]);
+        }
+        let status = self.query()?.status(&script_hash[..])?;
         Ok(json!(Value::Array(
             status
                 .history()
@@ -294,10 +651,48 @@ impl Connection {
         )))
     }
 
+    // Non-standard extension: [from_height, to_height, limit, after_txid] (all optional).
+    // Only the transactions of the returned page are loaded, so popular addresses' history can
+    // be fetched incrementally.
+    fn scripthash_get_history_page(
+        &self,
+        script_hash: &Sha256dHash,
+        params: &[Value],
+    ) -> Result<Value> {
+        let from_height = usize_from_value_or(params.get(0), "from_height", 0)?;
+        let to_height = match params.get(1) {
+            None | Some(Value::Null) => None,
+            Some(value) => {
+                let height = value.as_i64().chain_err(|| "non-integer to_height")?;
+                if height < 0 {
+                    None // include mempool transactions
+                } else {
+                    Some(height as u32)
+                }
+            }
+        };
+        let limit = usize_from_value_or(params.get(2), "limit", MAX_HISTORY_PAGE_SIZE)?;
+        let after_txid = match params.get(3) {
+            None | Some(Value::Null) => None,
+            value => Some(hash_from_value(value).chain_err(|| "bad after_txid")?),
+        };
+        let (page, total) = self.query()?.history_page(
+            &script_hash[..],
+            from_height as u32,
+            to_height,
+            after_txid.as_ref(),
+            limit.min(MAX_HISTORY_PAGE_SIZE),
+        )?;
+        let history: Vec<Value> = page.iter().map(|item| item.to_json()).collect();
+        Ok(json!({"history": history, "total": total}))
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        Ok(unspent_from_status(&self.query.status(&script_hash[..])?))
+        Ok(unspent_from_status(
+            &self.query()?.status(&script_hash[..])?,
+        ))
     }
 
     fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
@@ -305,8 +700,9 @@ impl Connection {
         let tx = tx.as_str().chain_err(|| "non-string tx")?;
         let tx = hex::decode(&tx).chain_err(|| "non-hex tx")?;
         let tx: Transaction = deserialize(&tx).chain_err(|| "failed to parse tx")?;
-        let txid = self.query.broadcast(&tx)?;
-        self.query.update_mempool()?;
+        let query = self.query()?;
+        let txid = query.broadcast(&tx)?;
+        query.update_mempool()?;
         if let Err(e) = self.sender.try_send(Message::PeriodicUpdate) {
             warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
         }
@@ -319,12 +715,12 @@ impl Connection {
             Some(value) => value.as_bool().chain_err(|| "non-bool verbose value")?,
             None => false,
         };
-        Ok(self.query.get_transaction(&tx_hash, verbose)?)
+        Ok(self.query()?.get_transaction(&tx_hash, verbose)?)
     }
 
     fn blockchain_transaction_get_confirmed_blockhash(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
-        let value = self.query.get_confirmed_blockhash(&tx_hash)?;
+        let value = self.query()?.get_confirmed_blockhash(&tx_hash)?;
         Ok(value)
     }
 
@@ -332,7 +728,7 @@ impl Connection {
         let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
         let height = usize_from_value(params.get(1), "height")?;
         let (merkle, pos) = self
-            .query
+            .query()?
             .get_merkle_proof(&tx_hash, height)
             .chain_err(|| "cannot create merkle proof")?;
         let merkle: Vec<String> = merkle.into_iter().map(|txid| txid.to_hex()).collect();
@@ -347,7 +743,7 @@ impl Connection {
         let tx_pos = usize_from_value(params.get(1), "tx_pos")?;
         let want_merkle = bool_from_value_or(params.get(2), "merkle", false)?;
 
-        let (txid, merkle) = self.query.get_id_from_pos(height, tx_pos, want_merkle)?;
+        let (txid, merkle) = self.query()?.get_id_from_pos(height, tx_pos, want_merkle)?;
 
         if !want_merkle {
             return Ok(json!(txid.to_hex()));
@@ -366,7 +762,10 @@ impl Connection {
             .latency
             .with_label_values(&[method])
             .start_timer();
+        let supported = method_protocol_version(method)
+            .map_or(true, |version| version <= self.protocol_version());
         let result = match method {
+            _ if !supported => Err(ErrorKind::MethodNotFound(method.to_owned()).into()),
             "blockchain.block.header" => self.blockchain_block_header(&params),
             "blockchain.block.headers" => self.blockchain_block_headers(&params),
             "blockchain.estimatefee" => self.blockchain_estimatefee(&params),
@@ -376,6 +775,7 @@ impl Connection {
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
+            "blockchain.scripthash.unsubscribe" => self.blockchain_scripthash_unsubscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
             "blockchain.transaction.get" => self.blockchain_transaction_get(&params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(&params),
@@ -386,8 +786,10 @@ impl Connection {
                 self.blockchain_transaction_id_from_pos(&params)
             }
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "server.add_peer" => self.server_add_peer(&params),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
+            "server.features" => self.server_features(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
             "server.ping" => Ok(Value::Null),
             "server.version" => self.server_version(params),
@@ -414,7 +816,7 @@ impl Connection {
             .start_timer();
         let mut result = vec![];
         if let Some(ref mut last_entry) = self.last_header_entry {
-            let entry = self.query.get_best_header()?;
+            let entry = self.backend.get_best_header()?;
             if *last_entry != entry {
                 *last_entry = entry;
                 let hex_header = hex::encode(serialize(last_entry.header()));
@@ -425,17 +827,21 @@ impl Connection {
                     "params": [header]}));
             }
         }
-        for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let status = self.query.status(&script_hash[..])?;
-            let new_status_hash = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
-            if new_status_hash == *status_hash {
-                continue;
+        if !self.status_hashes.is_empty() {
+            // scripthash subscriptions are possible only after the initial sync is over
+            let query = self.query()?;
+            for (script_hash, status_hash) in self.status_hashes.iter_mut() {
+                let status = query.status(&script_hash[..])?;
+                let new_status_hash = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
+                if new_status_hash == *status_hash {
+                    continue;
+                }
+                result.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [script_hash.to_hex(), new_status_hash]}));
+                *status_hash = new_status_hash;
             }
-            result.push(json!({
-                "jsonrpc": "2.0",
-                "method": "blockchain.scripthash.subscribe",
-                "params": [script_hash.to_hex(), new_status_hash]}));
-            *status_hash = new_status_hash;
         }
         timer.observe_duration();
         Ok(result)
@@ -451,12 +857,11 @@ impl Connection {
         Ok(())
     }
 
-    fn handle_replies(&mut self, receiver: Receiver<Message>) -> Result<()> {
-        fn parse_id_from_request(line: String) -> Result<(Value, Map)> {
-            let value: Value = from_str(&line).chain_err(|| ErrorKind::ParseError)?;
+    fn handle_request(&mut self, value: Value) -> Value {
+        fn parse_id_from_request(value: Value) -> Result<(Value, Map)> {
             let mut cmd = match value {
                 Value::Object(cmd) => cmd,
-                _ => bail!(ErrorKind::ParseError),
+                _ => bail!(ErrorKind::InvalidRequest("request must be an object")),
             };
             let id = cmd
                 .remove("id")
@@ -480,29 +885,71 @@ impl Connection {
             Ok((method, params))
         }
 
+        let (id, result) = match parse_id_from_request(value) {
+            Ok((id, cmd)) => match parse_method_and_params_from_request(cmd) {
+                Ok((method, params)) => {
+                    let result = self.handle_command(&method, &params, &id);
+                    (id, result)
+                }
+                Err(e) => (id, Err(e)),
+            },
+            Err(e) => (Value::Null, Err(e)),
+        };
+        match result {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => error_reply(id, &e),
+        }
+    }
+
+    // https://www.jsonrpc.org/specification#batch
+    // Notifications (i.e. requests without an id) are handled, but not replied to - so `None` is
+    // returned if the batch contains only notifications.
+    fn handle_batch(&mut self, requests: Vec<Value>) -> Option<Value> {
+        if requests.is_empty() {
+            let e = Error::from(ErrorKind::InvalidRequest("empty batch"));
+            return Some(error_reply(Value::Null, &e));
+        }
+        if requests.len() > self.max_batch_size {
+            warn!(
+                "[{}] batch of {} requests exceeds max_batch_size={}",
+                self.addr,
+                requests.len(),
+                self.max_batch_size
+            );
+            let e = Error::from(ErrorKind::InvalidRequest("batch is too large"));
+            return Some(error_reply(Value::Null, &e));
+        }
+        let mut replies = vec![];
+        for mut request in requests {
+            match request.as_object_mut() {
+                Some(cmd) if !cmd.contains_key("id") => {
+                    cmd.insert("id".to_owned(), Value::Null);
+                    self.handle_request(request); // a notification
+                }
+                _ => replies.push(self.handle_request(request)),
+            }
+        }
+        if replies.is_empty() {
+            None
+        } else {
+            Some(json!(replies))
+        }
+    }
+
+    fn handle_replies(&mut self, receiver: Receiver<Message>) -> Result<()> {
         loop {
             let msg = receiver.recv().chain_err(|| "channel closed")?;
             trace!("RPC {:?}", msg);
             match msg {
                 Message::Request(line) => {
-                    let (id, result) = match parse_id_from_request(line) {
-                        Ok((id, cmd)) => match parse_method_and_params_from_request(cmd) {
-                            Ok((method, params)) => {
-                                let result = self.handle_command(&method, &params, &id);
-                                (id, result)
-                            }
-                            Err(e) => (id, Err(e)),
-                        },
-                        Err(e) => (Value::Null, Err(e)),
-                    };
-                    let reply = match result {
-                        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
-                        Err(e) => {
-                            let error = json_rpc_error_from_error(&e);
-                            json!({"jsonrpc": "2.0", "id": id, "error": error})
-                        }
+                    let reply = match from_str::<Value>(&line).chain_err(|| ErrorKind::ParseError) {
+                        Ok(Value::Array(requests)) => self.handle_batch(requests),
+                        Ok(value) => Some(self.handle_request(value)),
+                        Err(e) => Some(error_reply(Value::Null, &e)),
                     };
-                    self.send_values(&[reply])?
+                    if let Some(reply) = reply {
+                        self.send_values(&[reply])?
+                    }
                 }
                 Message::PeriodicUpdate => {
                     let values = self
@@ -515,7 +962,7 @@ impl Connection {
         }
     }
 
-    fn parse_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    fn parse_requests(mut reader: BufReader<ClientStream>, tx: SyncSender<Message>) -> Result<()> {
         loop {
             let mut line = Vec::<u8>::new();
             reader
@@ -544,7 +991,7 @@ impl Connection {
     }
 
     pub fn run(mut self, receiver: Receiver<Message>) {
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
+        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone stream"));
         let sender = self.sender.clone();
         let child = spawn_thread("reader", || Connection::parse_requests(reader, sender));
         if let Err(e) = self.handle_replies(receiver) {
@@ -577,9 +1024,13 @@ pub enum Notification {
     Exit,
 }
 
+type AcceptorChannel = Channel<Option<(ClientStream, SocketAddr)>>;
+
 pub struct RPC {
     notification: Sender<Notification>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
+    #[cfg(feature = "tls")]
+    tls_acceptor: Option<Arc<tls::Acceptor>>,
 }
 
 struct Stats {
@@ -591,7 +1042,7 @@ impl RPC {
     fn start_notifier(
         notification: Channel<Notification>,
         senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        acceptor: Sender<Option<(ClientStream, SocketAddr)>>,
     ) {
         spawn_thread("notification", move || {
             for msg in notification.receiver().iter() {
@@ -614,28 +1065,74 @@ impl RPC {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::unbounded();
+    fn start_acceptor(addr: SocketAddr, chan: &AcceptorChannel) {
         let acceptor = chan.sender();
         spawn_thread("acceptor", move || {
             let listener =
                 TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
             info!(
-                "Electrum RPC server running on {} (protocol {})",
-                addr, PROTOCOL_VERSION
+                "Electrum RPC server running on {} (protocol {}-{})",
+                addr, MIN_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION
             );
             loop {
                 let (stream, addr) = listener.accept().expect("accept failed");
                 stream
                     .set_nonblocking(false)
                     .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
+                acceptor
+                    .send(Some((ClientStream::Tcp(stream), addr)))
+                    .expect("send failed");
             }
         });
-        chan
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>, metrics: &Metrics, relayfee: f64) -> RPC {
+    /// Accepts connections that require a (blocking) handshake, using a thread per handshake.
+    fn start_handshake_acceptor<F>(
+        name: &'static str,
+        addr: SocketAddr,
+        chan: &AcceptorChannel,
+        handshake: F,
+    ) where
+        F: Fn(TcpStream) -> Result<ClientStream> + Send + Sync + 'static,
+    {
+        let acceptor = chan.sender();
+        let handshake = Arc::new(handshake);
+        spawn_thread(&format!("{}_acceptor", name), move || {
+            let listener =
+                TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
+            info!("Electrum RPC-over-{} server running on {}", name, addr);
+            loop {
+                let (stream, addr) = listener.accept().expect("accept failed");
+                stream
+                    .set_nonblocking(false)
+                    .expect("failed to set connection as blocking");
+                let acceptor = acceptor.clone();
+                let handshake = Arc::clone(&handshake);
+                // don't block other clients during the handshake
+                spawn_thread(&format!("{}_handshake", name), move || {
+                    match (*handshake)(stream) {
+                        Ok(stream) => acceptor.send(Some((stream, addr))).expect("send failed"),
+                        Err(e) => warn!(
+                            "[{}] {} handshake failed: {}",
+                            addr,
+                            name,
+                            e.display_chain()
+                        ),
+                    }
+                });
+            }
+        });
+    }
+
+    pub fn start(
+        addr: SocketAddr,
+        tls: Option<&ElectrumTls>,
+        websocket_addr: Option<SocketAddr>,
+        backend: Arc<Backend>,
+        metrics: &Metrics,
+        relayfee: f64,
+        max_batch_size: usize,
+    ) -> RPC {
         let stats = Arc::new(Stats {
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrs_electrum_rpc", "Electrum RPC latency (seconds)"),
@@ -649,12 +1146,41 @@ impl RPC {
         stats.subscriptions.set(0);
         let notification = Channel::unbounded();
 
+        let acceptor = Channel::unbounded();
+        RPC::start_acceptor(addr, &acceptor);
+        #[cfg(feature = "tls")]
+        let tls_acceptor = tls.map(|tls| {
+            let tls_acceptor =
+                tls::Acceptor::load(&tls.cert_file, &tls.key_file).unwrap_or_else(|e| {
+                    panic!("failed to load TLS certificate: {}", e.display_chain())
+                });
+            let tls_acceptor = Arc::new(tls_acceptor);
+            let handshake_acceptor = Arc::clone(&tls_acceptor);
+            RPC::start_handshake_acceptor("TLS", tls.addr, &acceptor, move |stream| {
+                Ok(ClientStream::Tls(handshake_acceptor.accept(stream)?))
+            });
+            tls_acceptor
+        });
+        #[cfg(not(feature = "tls"))]
+        assert!(tls.is_none(), "TLS support requires the 'tls' feature");
+        if let Some(addr) = websocket_addr {
+            RPC::start_handshake_acceptor("WebSocket", addr, &acceptor, |stream| {
+                stream
+                    .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+                    .chain_err(|| "failed to set handshake timeout")?;
+                let raw = stream.try_clone().chain_err(|| "failed to clone socket")?;
+                let websocket = WebSocket::accept(ClientStream::Tcp(stream))?;
+                raw.set_read_timeout(None)
+                    .chain_err(|| "failed to reset read timeout")?;
+                Ok(ClientStream::WebSocket(Box::new(websocket)))
+            });
+        }
+
         RPC {
             notification: notification.sender(),
             server: Some(spawn_thread("rpc", move || {
                 let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
 
-                let acceptor = RPC::start_acceptor(addr);
                 RPC::start_notifier(notification, senders.clone(), acceptor.sender());
 
                 let mut threads = HashMap::new();
@@ -662,7 +1188,7 @@ impl RPC {
 
                 while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
                     // explicitely scope the shadowed variables for the new thread
-                    let query = Arc::clone(&query);
+                    let backend = Arc::clone(&backend);
                     let stats = Arc::clone(&stats);
                     let garbage_sender = garbage_sender.clone();
                     let (sender, receiver) = mpsc::sync_channel(10);
@@ -671,7 +1197,15 @@ impl RPC {
 
                     let spawned = spawn_thread("peer", move || {
                         info!("[{}] connected peer", addr);
-                        let conn = Connection::new(query, stream, addr, stats, relayfee, sender);
+                        let conn = Connection::new(
+                            backend,
+                            stream,
+                            addr,
+                            stats,
+                            relayfee,
+                            max_batch_size,
+                            sender,
+                        );
                         conn.run(receiver);
                         info!("[{}] disconnected peer", addr);
                         let _ = garbage_sender.send(std::thread::current().id());
@@ -701,10 +1235,21 @@ impl RPC {
 
                 trace!("RPC connections are closed");
             })),
+            #[cfg(feature = "tls")]
+            tls_acceptor,
         }
     }
 
     pub fn notify(&self) {
+        #[cfg(feature = "tls")]
+        {
+            if let Some(tls_acceptor) = &self.tls_acceptor {
+                // allow renewing the certificate without restarting (e.g. via SIGUSR1)
+                if let Err(e) = tls_acceptor.reload_if_modified() {
+                    warn!("failed to reload TLS certificate: {}", e.display_chain());
+                }
+            }
+        }
         self.notification.send(Notification::Periodic).unwrap();
     }
 }
@@ -719,3 +1264,177 @@ impl Drop for RPC {
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{bitcoind::FakeBitcoind, FakeStore};
+    use crate::index::{Index, RowFormat};
+    use crate::signal::Waiter;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    fn version(s: &str) -> ProtocolVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_server_features() {
+        let genesis_hash =
+            BlockHash::from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+                .unwrap();
+        let hosts = [
+            PublicHost {
+                hostname: "electrum.example.com".to_owned(),
+                tcp_port: Some(50001),
+                ssl_port: Some(50002),
+            },
+            PublicHost {
+                hostname: "example.onion".to_owned(),
+                tcp_port: Some(50001),
+                ssl_port: None,
+            },
+        ];
+        let features = server_features(&genesis_hash, &hosts);
+        assert_eq!(
+            features["genesis_hash"],
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+        );
+        assert_eq!(
+            features["hosts"],
+            json!({
+                "electrum.example.com": {"tcp_port": 50001, "ssl_port": 50002},
+                "example.onion": {"tcp_port": 50001, "ssl_port": null},
+            })
+        );
+        assert_eq!(features["protocol_min"], "1.4");
+        assert_eq!(features["protocol_max"], "1.5");
+        assert_eq!(features["hash_function"], "sha256");
+        assert_eq!(features["pruning"], Value::Null);
+        assert_eq!(
+            features["server_version"],
+            format!("electrs {}", ELECTRS_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_syncing_error() {
+        let e = Error::from(ErrorKind::Syncing(100, 200)).chain_err(|| "failed to get status");
+        let error = json_rpc_error_from_error(&e);
+        assert_eq!(error["code"], SYNCING_ERROR);
+        assert_eq!(error["message"], "server is syncing (height 100 of 200)");
+
+        let e = Error::from("failed to get status");
+        let error = json_rpc_error_from_error(&e);
+        assert_eq!(error["code"], BAD_REQUEST);
+        assert_eq!(error["message"], "failed to get status");
+    }
+
+    #[test]
+    fn test_protocol_version_parsing() {
+        assert_eq!(version("1.4"), ProtocolVersion::new(1, 4, 0));
+        assert_eq!(version("1.4.2"), ProtocolVersion::new(1, 4, 2));
+        assert_eq!(version("1.4.2").to_string(), "1.4.2");
+        assert_eq!(version("1.4.0").to_string(), "1.4");
+        assert!(version("1.4") < version("1.4.2"));
+        assert!(version("1.4.2") < version("1.10"));
+        assert!("1".parse::<ProtocolVersion>().is_err());
+        assert!("1.4.x".parse::<ProtocolVersion>().is_err());
+    }
+
+    #[test]
+    fn test_protocol_version_negotiation() {
+        let negotiate = |min, max| negotiate_protocol_version(version(min), version(max));
+        assert_eq!(negotiate("1.4", "1.4"), Some(version("1.4")));
+        assert_eq!(negotiate("1.2", "1.4.2"), Some(version("1.4.2")));
+        assert_eq!(negotiate("1.4", "1.4.3"), Some(version("1.4.2")));
+        assert_eq!(negotiate("1.4", "2.0"), Some(version("1.5")));
+        assert_eq!(negotiate("1.4.2", "1.4"), None);
+        assert_eq!(negotiate("1.1", "1.3"), None);
+        assert_eq!(negotiate("1.6", "1.7"), None);
+    }
+
+    // Returns a connection to a server that hasn't finished its initial sync yet.
+    fn syncing_connection(bitcoind: &FakeBitcoind, metrics: &Metrics) -> Connection {
+        let genesis = genesis_block(Network::Regtest);
+        let daemon = bitcoind.daemon(&Waiter::start(), metrics);
+        let index = Index::load(&FakeStore, &daemon, metrics, 10, RowFormat::Compact).unwrap();
+        let progress = Arc::new(SyncProgress::new(metrics, &index));
+        let path = std::env::temp_dir().join(format!("electrs-rpc-{}", std::process::id()));
+        let features = server_features(&genesis.block_hash(), &[]);
+        let peers = PeerManager::new(&path, &[], genesis.block_hash(), features.clone());
+        let banner = Banner::new("Welcome", None, None).unwrap();
+        let backend = Backend::new(daemon, progress, peers, banner);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let addr = stream.local_addr().unwrap();
+        let stats = Arc::new(Stats {
+            latency: metrics.histogram_vec(
+                HistogramOpts::new("electrs_test_rpc", "Electrum RPC latency (seconds)"),
+                &["method"],
+            ),
+            subscriptions: metrics.gauge(MetricOpts::new(
+                "electrs_test_subscriptions",
+                "# of Electrum subscriptions",
+            )),
+        });
+        let (sender, _receiver) = mpsc::sync_channel(10);
+        let stream = ClientStream::Tcp(stream);
+        Connection::new(backend, stream, addr, stats, 0.0, 10, sender)
+    }
+
+    #[test]
+    fn test_syncing_connection() {
+        let genesis = genesis_block(Network::Regtest);
+        let bitcoind = FakeBitcoind::start(vec![genesis.clone()]);
+        let metrics = Metrics::new(([127, 0, 0, 1], 0).into());
+        let mut conn = syncing_connection(&bitcoind, &metrics);
+        let features = server_features(&genesis.block_hash(), &[]);
+        let mut call = |method: &str, params: Value| {
+            conn.handle_request(json!({"id": 0, "method": method, "params": params}))
+        };
+
+        // headers are fetched from bitcoind
+        let header = hex::encode(serialize(&genesis.header));
+        assert_eq!(
+            call("blockchain.block.header", json!([0]))["result"],
+            header
+        );
+        let reply = call("blockchain.headers.subscribe", json!([]));
+        assert_eq!(reply["result"], json!({"hex": header, "height": 0}));
+
+        // `server.*` methods are served
+        assert_eq!(call("server.features", json!([]))["result"], features);
+        let reply = call("server.banner", json!([]));
+        assert_eq!(reply["result"], "Welcome\n/Satoshi:0.21.0/");
+        assert_eq!(
+            call("server.peers.subscribe", json!([]))["result"],
+            json!([])
+        );
+
+        // the other queries fail until the initial sync is over
+        let script_hash = "0000000000000000000000000000000000000000000000000000000000000000";
+        let reply = call("blockchain.scripthash.get_balance", json!([script_hash]));
+        assert_eq!(reply["error"]["code"], SYNCING_ERROR);
+    }
+
+    #[test]
+    fn test_batch_notifications() {
+        let bitcoind = FakeBitcoind::start(vec![genesis_block(Network::Regtest)]);
+        let metrics = Metrics::new(([127, 0, 0, 1], 0).into());
+        let mut conn = syncing_connection(&bitcoind, &metrics);
+
+        let notification = json!({"method": "server.ping", "params": []});
+        let request = json!({"id": 1, "method": "server.ping", "params": []});
+        let reply = conn.handle_batch(vec![notification.clone(), request]);
+        assert_eq!(
+            reply,
+            Some(json!([{"jsonrpc": "2.0", "id": 1, "result": null}]))
+        );
+        assert_eq!(
+            conn.handle_batch(vec![notification.clone(), notification]),
+            None
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use crossbeam_channel as channel;
 use crossbeam_channel::RecvTimeoutError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -8,31 +10,57 @@ use crate::errors::*;
 #[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
     receiver: channel::Receiver<i32>,
+    sender: channel::Sender<i32>, // for triggering updates without a signal
+    reload: Arc<AtomicBool>,      // set on SIGUSR1, regardless of the thread receiving it
 }
 
-fn notify(signals: &[i32]) -> channel::Receiver<i32> {
+fn notify(
+    signals: &[i32],
+    reload: Arc<AtomicBool>,
+) -> (channel::Sender<i32>, channel::Receiver<i32>) {
     let (s, r) = channel::bounded(1);
+    let sender = s.clone();
     let signals =
         signal_hook::iterator::Signals::new(signals).expect("failed to register signal hook");
     thread::spawn(move || {
         for signal in signals.forever() {
+            if signal == signal_hook::SIGUSR1 {
+                reload.store(true, Ordering::SeqCst);
+            }
             s.send(signal)
                 .unwrap_or_else(|_| panic!("failed to send signal {}", signal));
         }
     });
-    r
+    (sender, r)
 }
 
 impl Waiter {
     pub fn start() -> Waiter {
+        let reload = Arc::new(AtomicBool::new(false));
+        let signals = [
+            signal_hook::SIGINT,
+            signal_hook::SIGTERM,
+            signal_hook::SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
+        ];
+        let (sender, receiver) = notify(&signals, Arc::clone(&reload));
         Waiter {
-            receiver: notify(&[
-                signal_hook::SIGINT,
-                signal_hook::SIGTERM,
-                signal_hook::SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
-            ]),
+            receiver,
+            sender,
+            reload,
         }
     }
+
+    /// Wakes up a waiting thread, as if SIGUSR1 was received (e.g. on a ZMQ notification).
+    pub fn trigger(&self) {
+        // if the channel is full, a wake-up is already pending
+        let _ = self.sender.try_send(signal_hook::SIGUSR1);
+    }
+
+    /// Returns whether SIGUSR1 was received since the last call (e.g. for reloading files).
+    pub fn reload_requested(&self) -> bool {
+        self.reload.swap(false, Ordering::SeqCst)
+    }
+
     pub fn wait(&self, duration: Duration) -> Result<()> {
         match self.receiver.recv_timeout(duration) {
             Ok(sig) => {
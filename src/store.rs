@@ -1,7 +1,76 @@
 use std::path::{Path, PathBuf};
 
+use crate::errors::*;
+use crate::index::RowFormat;
 use crate::util::Bytes;
 
+/// Version of the DB rows' layout, to be incremented (with a matching `MIGRATIONS` entry, if
+/// possible) on every incompatible change.
+const DB_VERSION: u32 = 3;
+
+/// Stored in the `V` row, and checked when the DB is opened.
+/// Before version 3, only the version was stored (and extended DBs had an `X` marker row).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Metadata {
+    version: u32,
+    format: RowFormat,
+}
+
+/// Upgrades a DB from `from_version` to `from_version + 1`, by adding rows' updates (including
+/// the new `V` row) to `batch`.
+struct Migration {
+    from_version: u32,
+    description: &'static str,
+    run: fn(&DBStore, &mut rocksdb::WriteBatch) -> Result<()>,
+}
+
+fn add_version_row(_store: &DBStore, batch: &mut rocksdb::WriteBatch) -> Result<()> {
+    let row = version_row(2);
+    batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+    Ok(())
+}
+
+// Replaces the extended rows' marker by the format stored in the `V` row.
+fn add_row_format(store: &DBStore, batch: &mut rocksdb::WriteBatch) -> Result<()> {
+    let format = if store.get(b"X").is_some() {
+        RowFormat::Extended
+    } else {
+        RowFormat::Compact
+    };
+    batch.delete(b"X").unwrap();
+    let row = metadata_row(&Metadata { version: 3, format });
+    batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+    Ok(())
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from_version: 1,
+        description: "add DB version row",
+        run: add_version_row,
+    },
+    Migration {
+        from_version: 2,
+        description: "store row format in DB version row",
+        run: add_row_format,
+    },
+];
+
+// Used by versions 1 and 2 (which didn't store the row format).
+fn version_row(version: u32) -> Row {
+    Row {
+        key: b"V".to_vec(),
+        value: bincode::serialize(&version).unwrap(),
+    }
+}
+
+fn metadata_row(metadata: &Metadata) -> Row {
+    Row {
+        key: b"V".to_vec(),
+        value: bincode::serialize(metadata).unwrap(),
+    }
+}
+
 #[derive(Clone)]
 pub struct Row {
     pub key: Bytes,
@@ -21,6 +90,11 @@ pub trait ReadStore: Sync {
 
 pub trait WriteStore: Sync {
     fn write<I: IntoIterator<Item = Row>>(&self, rows: I);
+    /// Atomically deletes `keys` and writes `rows` (e.g. for undoing reorged blocks).
+    fn delete_and_write<K, I>(&self, keys: K, rows: I)
+    where
+        K: IntoIterator<Item = Bytes>,
+        I: IntoIterator<Item = Row>;
     fn flush(&self);
 }
 
@@ -29,6 +103,7 @@ struct Options {
     path: PathBuf,
     bulk_import: bool,
     low_memory: bool,
+    format: RowFormat,
 }
 
 pub struct DBStore {
@@ -37,7 +112,7 @@ pub struct DBStore {
 }
 
 impl DBStore {
-    fn open_opts(opts: Options) -> Self {
+    fn open_opts(opts: Options) -> Result<Self> {
         debug!("opening DB at {:?}", opts.path);
         let mut db_opts = rocksdb::Options::default();
         db_opts.create_if_missing(true);
@@ -55,21 +130,120 @@ impl DBStore {
 
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
-        DBStore {
+        let store = DBStore {
             db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
             opts,
-        }
+        };
+        store
+            .upgrade()
+            .chain_err(|| format!("incompatible DB at {:?}", store.opts.path))?;
+        Ok(store)
     }
 
-    /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, low_memory: bool) -> Self {
+    /// Opens a new RocksDB at the specified location (migrating its rows, if needed).
+    /// Fails if the DB uses a different row format (unless nothing is indexed yet).
+    pub fn open(path: &Path, low_memory: bool, format: RowFormat) -> Result<Self> {
         DBStore::open_opts(Options {
             path: path.to_path_buf(),
             bulk_import: true,
             low_memory,
+            format,
         })
     }
 
+    /// Deletes the RocksDB at the specified location (e.g. before reindexing).
+    pub fn destroy(path: &Path) {
+        info!("deleting DB at {:?}", path);
+        rocksdb::DB::destroy(&rocksdb::Options::default(), path).unwrap();
+    }
+
+    fn read_version(&self) -> Result<Option<u32>> {
+        match self.get(b"V") {
+            // the version is serialized first (also as part of the metadata)
+            Some(value) => Ok(Some(
+                bincode::deserialize(value.get(..4).unwrap_or(&value[..]))
+                    .chain_err(|| "invalid DB version")?,
+            )),
+            None if self.is_empty() => Ok(None), // a new DB
+            None => Ok(Some(1)),                 // created before versioning
+        }
+    }
+
+    fn read_metadata(&self) -> Result<Metadata> {
+        let value = self.get(b"V").chain_err(|| "missing DB metadata")?;
+        bincode::deserialize(&value).chain_err(|| "invalid DB metadata")
+    }
+
+    fn write_metadata(&self) {
+        let mut batch = rocksdb::WriteBatch::default();
+        let row = metadata_row(&Metadata {
+            version: DB_VERSION,
+            format: self.opts.format,
+        });
+        batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+        self.write_batch_durably(batch);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.db
+            .iterator(rocksdb::IteratorMode::Start)
+            .next()
+            .is_none()
+    }
+
+    fn upgrade(&self) -> Result<()> {
+        let mut version = match self.read_version()? {
+            Some(version) => version,
+            None => {
+                self.write_metadata();
+                return Ok(());
+            }
+        };
+        if version > DB_VERSION {
+            bail!(
+                "DB version {} is newer than the supported version {}: \
+                 upgrade electrs, or run with --reindex to rebuild the DB",
+                version,
+                DB_VERSION
+            );
+        }
+        while version < DB_VERSION {
+            let migration = MIGRATIONS
+                .iter()
+                .find(|m| m.from_version == version)
+                .chain_err(|| {
+                    format!(
+                        "DB version {} cannot be migrated to version {}: \
+                         run with --reindex to rebuild the DB",
+                        version, DB_VERSION
+                    )
+                })?;
+            info!(
+                "migrating DB from version {} to {}: {}",
+                version,
+                version + 1,
+                migration.description
+            );
+            let mut batch = rocksdb::WriteBatch::default();
+            (migration.run)(self, &mut batch)
+                .chain_err(|| format!("failed to migrate DB version {}", version))?;
+            self.write_batch_durably(batch); // each migration is applied atomically
+            version += 1;
+        }
+        let stored = self.read_metadata()?.format;
+        if stored != self.opts.format {
+            if self.iter_scan(b"B").next().is_some() {
+                bail!(
+                    "DB uses {:?} rows instead of {:?} rows: run with --reindex to rebuild it",
+                    stored,
+                    self.opts.format
+                );
+            }
+            self.write_metadata(); // nothing is indexed yet
+        }
+        Ok(())
+    }
+
     pub fn enable_compaction(self) -> Self {
         let mut opts = self.opts.clone();
         if opts.bulk_import {
@@ -146,24 +320,49 @@ impl ReadStore for DBStore {
     }
 }
 
-impl WriteStore for DBStore {
-    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
-        let mut batch = rocksdb::WriteBatch::default();
-        for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
-        }
+impl DBStore {
+    fn write_batch(&self, batch: rocksdb::WriteBatch) {
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(!self.opts.bulk_import);
         opts.disable_wal(self.opts.bulk_import);
         self.db.write_opt(batch, &opts).unwrap();
     }
 
-    fn flush(&self) {
+    // Doesn't disable the WAL during bulk import, so the batch is persisted immediately.
+    fn write_batch_durably(&self, batch: rocksdb::WriteBatch) {
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
         opts.disable_wal(false);
-        let empty = rocksdb::WriteBatch::default();
-        self.db.write_opt(empty, &opts).unwrap();
+        self.db.write_opt(batch, &opts).unwrap();
+    }
+}
+
+impl WriteStore for DBStore {
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
+        let mut batch = rocksdb::WriteBatch::default();
+        for row in rows {
+            batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+        }
+        self.write_batch(batch);
+    }
+
+    fn delete_and_write<K, I>(&self, keys: K, rows: I)
+    where
+        K: IntoIterator<Item = Bytes>,
+        I: IntoIterator<Item = Row>,
+    {
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete(key.as_slice()).unwrap();
+        }
+        for row in rows {
+            batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+        }
+        self.write_batch(batch);
+    }
+
+    fn flush(&self) {
+        self.write_batch_durably(rocksdb::WriteBatch::default());
     }
 }
 
@@ -191,3 +390,106 @@ pub fn is_fully_compacted(store: &dyn ReadStore) -> bool {
     let marker = store.get(&full_compaction_marker().key);
     marker.is_some()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("electrs-{}-{}", name, std::process::id()));
+        DBStore::destroy(&path);
+        path
+    }
+
+    fn block_row() -> Row {
+        Row {
+            key: b"B".to_vec(),
+            value: vec![],
+        }
+    }
+
+    #[test]
+    fn test_new_db_version() {
+        let path = temp_db_path("new");
+        let store = DBStore::open(&path, false, RowFormat::Extended).unwrap();
+        assert_eq!(store.read_version().unwrap(), Some(DB_VERSION));
+        let metadata = store.read_metadata().unwrap();
+        assert_eq!(metadata.format, RowFormat::Extended);
+        drop(store);
+        DBStore::destroy(&path);
+    }
+
+    #[test]
+    fn test_migrate_unversioned_db() {
+        let path = temp_db_path("unversioned");
+        {
+            let store = DBStore::open(&path, false, RowFormat::Compact).unwrap();
+            let keys = vec![b"V".to_vec()];
+            store.delete_and_write(keys, vec![full_compaction_marker()]);
+            store.flush();
+            assert_eq!(store.read_version().unwrap(), Some(1));
+        }
+        let store = DBStore::open(&path, false, RowFormat::Compact).unwrap();
+        assert_eq!(store.read_version().unwrap(), Some(DB_VERSION));
+        assert!(is_fully_compacted(&store));
+        drop(store);
+        DBStore::destroy(&path);
+    }
+
+    #[test]
+    fn test_migrate_extended_marker() {
+        let path = temp_db_path("marker");
+        {
+            let store = DBStore::open(&path, false, RowFormat::Compact).unwrap();
+            let marker = Row {
+                key: b"X".to_vec(),
+                value: vec![],
+            };
+            store.write(vec![version_row(2), marker, block_row()]);
+            store.flush();
+        }
+        // The marker is replaced by the metadata, which is checked when the DB is opened
+        assert!(DBStore::open(&path, false, RowFormat::Compact).is_err());
+        let store = DBStore::open(&path, false, RowFormat::Extended).unwrap();
+        let expected = Metadata {
+            version: DB_VERSION,
+            format: RowFormat::Extended,
+        };
+        assert_eq!(store.read_metadata().unwrap(), expected);
+        assert!(store.get(b"X").is_none());
+        assert!(store.get(b"B").is_some());
+        drop(store);
+        DBStore::destroy(&path);
+    }
+
+    #[test]
+    fn test_row_format_mismatch() {
+        let path = temp_db_path("format");
+        {
+            let store = DBStore::open(&path, false, RowFormat::Compact).unwrap();
+            store.write(vec![block_row()]);
+            store.flush();
+        }
+        assert!(DBStore::open(&path, false, RowFormat::Extended).is_err());
+        DBStore::destroy(&path);
+
+        // An empty index may be used with any format
+        drop(DBStore::open(&path, false, RowFormat::Compact).unwrap());
+        let store = DBStore::open(&path, false, RowFormat::Extended).unwrap();
+        assert_eq!(store.read_metadata().unwrap().format, RowFormat::Extended);
+        drop(store);
+        DBStore::destroy(&path);
+    }
+
+    #[test]
+    fn test_newer_db_version() {
+        let path = temp_db_path("newer");
+        {
+            let store = DBStore::open(&path, false, RowFormat::Compact).unwrap();
+            store.write(vec![version_row(DB_VERSION + 1)]);
+            store.flush();
+        }
+        assert!(DBStore::open(&path, false, RowFormat::Compact).is_err());
+        DBStore::destroy(&path);
+    }
+}
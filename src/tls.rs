@@ -0,0 +1,242 @@
+//! Electrum RPC over TLS.
+//!
+//! A TLS session can't be read and written concurrently, but the reading thread may wait for a
+//! long time (e.g. on an idle connection) while notifications are written. So the session's
+//! ciphertext is read from the socket without locking the session (and then buffered for it),
+//! while the session writes its ciphertext directly to the socket.
+
+use native_tls::{Identity, TlsAcceptor};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::errors::*;
+
+// Limits the time a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_BUFFER_SIZE: usize = 16 << 10; // a TLS record is at most 16kB (+ overhead)
+
+fn last_modified(path: &Path) -> Result<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .chain_err(|| format!("failed to stat {:?}", path))
+}
+
+fn load_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let cert = fs::read(cert_file).chain_err(|| format!("failed to read {:?}", cert_file))?;
+    let key = fs::read(key_file).chain_err(|| format!("failed to read {:?}", key_file))?;
+    // The key must be PEM-encoded PKCS#8 (e.g. Let's Encrypt `privkey.pem`)
+    let identity = Identity::from_pkcs8(&cert, &key).chain_err(|| {
+        format!(
+            "invalid TLS certificate {:?} or key {:?}",
+            cert_file, key_file
+        )
+    })?;
+    TlsAcceptor::new(identity).chain_err(|| "failed to create TLS acceptor")
+}
+
+struct Loaded {
+    acceptor: Arc<TlsAcceptor>,
+    modified: SystemTime,
+}
+
+/// Accepts TLS connections, using a certificate that can be reloaded (e.g. after renewal).
+pub struct Acceptor {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+impl Acceptor {
+    pub fn load(cert_file: &Path, key_file: &Path) -> Result<Acceptor> {
+        let acceptor = Acceptor {
+            cert_file: cert_file.to_path_buf(),
+            key_file: key_file.to_path_buf(),
+            loaded: RwLock::new(Loaded {
+                acceptor: Arc::new(load_acceptor(cert_file, key_file)?),
+                modified: SystemTime::UNIX_EPOCH,
+            }),
+        };
+        acceptor.loaded.write().unwrap().modified = acceptor.modified()?;
+        Ok(acceptor)
+    }
+
+    fn modified(&self) -> Result<SystemTime> {
+        Ok(last_modified(&self.cert_file)?.max(last_modified(&self.key_file)?))
+    }
+
+    /// Reloads the certificate and key, if any of them was modified since last load.
+    pub fn reload_if_modified(&self) -> Result<bool> {
+        let modified = self.modified()?;
+        if modified == self.loaded.read().unwrap().modified {
+            return Ok(false);
+        }
+        let acceptor = load_acceptor(&self.cert_file, &self.key_file)?;
+        *self.loaded.write().unwrap() = Loaded {
+            acceptor: Arc::new(acceptor),
+            modified,
+        };
+        info!("reloaded TLS certificate from {:?}", self.cert_file);
+        Ok(true)
+    }
+
+    /// Performs the server-side TLS handshake (blocking the current thread).
+    pub fn accept(&self, socket: TcpStream) -> Result<TlsStream> {
+        let acceptor = Arc::clone(&self.loaded.read().unwrap().acceptor);
+        socket
+            .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+            .chain_err(|| "failed to set handshake timeout")?;
+        let transport = Transport {
+            socket: socket.try_clone().chain_err(|| "failed to clone socket")?,
+            incoming: vec![],
+            eof: false,
+            handshake: true,
+        };
+        let mut session = acceptor
+            .accept(transport)
+            .map_err(|err| format!("TLS handshake failed: {}", err))?;
+        session.get_mut().handshake = false;
+        socket
+            .set_read_timeout(None)
+            .chain_err(|| "failed to reset read timeout")?;
+        Ok(TlsStream {
+            session: Arc::new(Mutex::new(session)),
+            socket,
+        })
+    }
+}
+
+/// The TLS session's underlying stream.
+struct Transport {
+    socket: TcpStream,
+    incoming: Vec<u8>, // ciphertext received by `TlsStream::read()`, to be used by the session
+    eof: bool,
+    handshake: bool, // the socket is read directly only during the handshake
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.handshake {
+            return self.socket.read(buf);
+        }
+        if self.incoming.is_empty() {
+            if self.eof {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into()); // see `TlsStream::read()`
+        }
+        let len = buf.len().min(self.incoming.len());
+        buf[..len].copy_from_slice(&self.incoming[..len]);
+        self.incoming.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+/// A TLS connection, which can be read and written from different threads.
+pub struct TlsStream {
+    session: Arc<Mutex<native_tls::TlsStream<Transport>>>,
+    socket: TcpStream, // read without locking the session (and used for shutting it down)
+}
+
+impl TlsStream {
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            session: Arc::clone(&self.session),
+            socket: self.socket.try_clone()?,
+        })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut ciphertext = [0u8; READ_BUFFER_SIZE];
+        loop {
+            match self.session.lock().unwrap().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+            // The session needs more ciphertext: wait for it, without blocking the writers
+            let len = self.socket.read(&mut ciphertext)?;
+            let mut session = self.session.lock().unwrap();
+            let transport = session.get_mut();
+            transport.incoming.extend_from_slice(&ciphertext[..len]);
+            transport.eof = len == 0;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.session.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_write_while_reading() {
+        let fixtures = Path::new("src").join("tests").join("fixtures");
+        let cert_file = fixtures.join("tls_cert.pem");
+        let acceptor = Acceptor::load(&cert_file, &fixtures.join("tls_key.pem")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let cert = native_tls::Certificate::from_pem(&fs::read(cert_file).unwrap()).unwrap();
+            let connector = native_tls::TlsConnector::builder()
+                .add_root_certificate(cert)
+                .disable_built_in_roots(true)
+                .build()
+                .unwrap();
+            let socket = TcpStream::connect(addr).unwrap();
+            let mut stream = connector.connect("localhost", socket).unwrap();
+            let mut notification = [0u8; 6];
+            stream.read_exact(&mut notification).unwrap();
+            assert_eq!(&notification, b"notify");
+            stream.write_all(b"request").unwrap();
+            stream.flush().unwrap();
+        });
+        let (socket, _) = listener.accept().unwrap();
+        let mut stream = acceptor.accept(socket).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+
+        // the reader waits for the request, which is sent only after the notification is received
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            let mut request = [0u8; 7];
+            stream.read_exact(&mut request).unwrap();
+            tx.send(request).unwrap();
+        });
+        thread::sleep(Duration::from_millis(100)); // let the reader wait for data
+        writer.write_all(b"notify").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(&rx.recv().unwrap(), b"request");
+        reader.join().unwrap();
+        client.join().unwrap();
+    }
+}
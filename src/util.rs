@@ -36,6 +36,14 @@ pub struct HeaderEntry {
 }
 
 impl HeaderEntry {
+    pub fn new(height: usize, header: BlockHeader) -> HeaderEntry {
+        HeaderEntry {
+            height,
+            hash: header.block_hash(),
+            header,
+        }
+    }
+
     pub fn hash(&self) -> &BlockHash {
         &self.hash
     }
@@ -0,0 +1,396 @@
+use bitcoin::hashes::{sha1, Hash};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+
+use crate::errors::*;
+use crate::rpc::ClientStream;
+
+// https://tools.ietf.org/html/rfc6455#section-1.3
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const BAD_REQUEST_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n";
+
+const MAX_REQUEST_SIZE: usize = 8 << 10; // for the HTTP upgrade request
+const MAX_MESSAGE_SIZE: usize = 16 << 20; // allows broadcasting large transactions
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+fn accept_key(key: &str) -> String {
+    let hash = sha1::Hash::hash(format!("{}{}", key, ACCEPT_GUID).as_bytes());
+    base64::encode(&hash[..])
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Frame> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0F;
+    if header[1] & 0x80 == 0 {
+        return Err(protocol_error("unmasked client frame"));
+    }
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(protocol_error("frame is too large"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    // the payload buffer grows as the data arrives (instead of trusting the frame's length)
+    let mut payload = vec![];
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        fin,
+        opcode,
+        payload,
+    })
+}
+
+fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode); // server frames are never fragmented nor masked
+    if payload.len() < 126 {
+        frame.push(payload.len() as u8);
+    } else if payload.len() <= 0xFFFF {
+        frame.push(126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+fn read_request(stream: &mut ClientStream) -> Result<Vec<String>> {
+    // Read byte-by-byte, in order not to consume any frame data following the request
+    let mut request = vec![];
+    let mut byte = [0u8; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_SIZE {
+            bail!("upgrade request is too large");
+        }
+        stream
+            .read_exact(&mut byte)
+            .chain_err(|| "failed to read upgrade request")?;
+        request.push(byte[0]);
+    }
+    let request = String::from_utf8(request).chain_err(|| "invalid UTF8 upgrade request")?;
+    Ok(request.lines().map(str::to_owned).collect())
+}
+
+fn get_header<'a>(headers: &HashMap<String, &'a str>, name: &str) -> Result<&'a str> {
+    Ok(*headers
+        .get(name)
+        .chain_err(|| format!("missing {} header", name))?)
+}
+
+fn parse_upgrade_request(lines: &[String]) -> Result<String> {
+    let request_line = lines.first().chain_err(|| "missing request line")?;
+    if !request_line.starts_with("GET ") {
+        bail!("invalid request line: {:?}", request_line);
+    }
+    let mut headers = HashMap::new();
+    for line in &lines[1..] {
+        let parts: Vec<&str> = line.splitn(2, ':').collect();
+        if parts.len() == 2 {
+            headers.insert(parts[0].trim().to_lowercase(), parts[1].trim());
+        }
+    }
+    let header = |name: &str| get_header(&headers, name);
+    if !header("upgrade")?.eq_ignore_ascii_case("websocket") {
+        bail!("invalid Upgrade header: {:?}", header("upgrade")?);
+    }
+    if !header("connection")?
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    {
+        bail!("invalid Connection header: {:?}", header("connection")?);
+    }
+    if header("sec-websocket-version")? != "13" {
+        bail!(
+            "unsupported WebSocket version: {:?}",
+            header("sec-websocket-version")?
+        );
+    }
+    Ok(header("sec-websocket-key")?.to_owned())
+}
+
+/// Electrum JSON-RPC over WebSocket, where each text message contains a single JSON value.
+///
+/// In order to reuse the line-based `Connection` handling, incoming messages are read as
+/// newline-terminated lines, and each outgoing line is sent as a separate text message.
+pub struct WebSocket {
+    stream: ClientStream,
+    writer: Arc<Mutex<ClientStream>>, // shared with the reading side (for control frames)
+    incoming: Vec<u8>,
+    incoming_pos: usize,
+    outgoing: Vec<u8>,
+}
+
+impl WebSocket {
+    /// Performs the server-side opening handshake (blocking the current thread).
+    pub fn accept(mut stream: ClientStream) -> Result<WebSocket> {
+        let request = read_request(&mut stream).and_then(|lines| parse_upgrade_request(&lines));
+        let key = match request {
+            Ok(key) => key,
+            Err(e) => {
+                let _ = stream.write_all(BAD_REQUEST_RESPONSE);
+                return Err(e).chain_err(|| "invalid WebSocket upgrade request");
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        );
+        stream
+            .write_all(response.as_bytes())
+            .chain_err(|| "failed to send upgrade response")?;
+        let writer = stream.try_clone().chain_err(|| "failed to clone stream")?;
+        Ok(WebSocket {
+            stream,
+            writer: Arc::new(Mutex::new(writer)),
+            incoming: vec![],
+            incoming_pos: 0,
+            outgoing: vec![],
+        })
+    }
+
+    pub fn try_clone(&self) -> io::Result<WebSocket> {
+        Ok(WebSocket {
+            stream: self.stream.try_clone()?,
+            writer: Arc::clone(&self.writer),
+            incoming: vec![],
+            incoming_pos: 0,
+            outgoing: vec![],
+        })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn send(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        write_frame(&mut *self.writer.lock().unwrap(), opcode, payload)
+    }
+
+    /// Returns the next data message, or `None` if the connection was closed.
+    fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message: Option<Vec<u8>> = None;
+        loop {
+            let frame = match read_frame(&mut self.stream) {
+                Ok(frame) => frame,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            // control frames may be interleaved with a fragmented message's frames
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY if message.is_none() => message = Some(frame.payload),
+                OPCODE_CONTINUATION if message.is_some() => {
+                    let data = message.as_mut().unwrap();
+                    if data.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                        return Err(protocol_error("message is too large"));
+                    }
+                    data.extend(frame.payload);
+                }
+                OPCODE_CLOSE => {
+                    let _ = self.send(OPCODE_CLOSE, &frame.payload[..frame.payload.len().min(2)]);
+                    return Ok(None);
+                }
+                OPCODE_PING => {
+                    self.send(OPCODE_PONG, &frame.payload)?;
+                    continue;
+                }
+                OPCODE_PONG => continue,
+                _ => return Err(protocol_error("unexpected frame")),
+            }
+            if frame.fin {
+                return Ok(message); // the last frame of a data message
+            }
+        }
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming_pos == self.incoming.len() {
+            let mut message = match self.read_message()? {
+                Some(message) => message,
+                None => return Ok(0), // EOF
+            };
+            // JSON allows replacing whitespace, so each message becomes a single line
+            for byte in message.iter_mut() {
+                if *byte == b'\n' || *byte == b'\r' {
+                    *byte = b' ';
+                }
+            }
+            message.push(b'\n');
+            self.incoming = message;
+            self.incoming_pos = 0;
+        }
+        let len = buf.len().min(self.incoming.len() - self.incoming_pos);
+        buf[..len].copy_from_slice(&self.incoming[self.incoming_pos..self.incoming_pos + len]);
+        self.incoming_pos += len;
+        Ok(len)
+    }
+}
+
+impl Write for WebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        while let Some(end) = self.outgoing.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.outgoing.drain(..=end).collect();
+            self.send(OPCODE_TEXT, &line[..end])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_accept_key() {
+        // https://tools.ietf.org/html/rfc6455#section-1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_upgrade_request() {
+        let request = "GET /electrum HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let lines: Vec<String> = request.lines().map(str::to_owned).collect();
+        assert_eq!(
+            parse_upgrade_request(&lines).unwrap(),
+            "dGhlIHNhbXBsZSBub25jZQ=="
+        );
+        let invalid = &lines[..lines.len() - 2]; // missing version
+        assert!(parse_upgrade_request(invalid).is_err());
+    }
+
+    #[test]
+    fn test_frames() {
+        // A single-frame masked text message (https://tools.ietf.org/html/rfc6455#section-5.7)
+        let data = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+        let frame = read_frame(&mut Cursor::new(&data[..])).unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, OPCODE_TEXT);
+        assert_eq!(frame.payload, b"Hello".to_vec());
+
+        // Unmasked frames must not be sent by clients
+        let data = b"\x81\x05Hello";
+        assert!(read_frame(&mut Cursor::new(&data[..])).is_err());
+
+        // A truncated frame (claiming a large payload)
+        let mut data = b"\x81\xff".to_vec();
+        data.extend_from_slice(&(MAX_MESSAGE_SIZE as u64).to_be_bytes());
+        data.extend_from_slice(b"\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58");
+        let err = read_frame(&mut Cursor::new(&data[..])).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut buf = vec![];
+        write_frame(&mut buf, OPCODE_TEXT, b"Hello").unwrap();
+        assert_eq!(buf, b"\x81\x05Hello".to_vec());
+
+        let payload = vec![0u8; 256];
+        let mut buf = vec![];
+        write_frame(&mut buf, OPCODE_BINARY, &payload).unwrap();
+        assert_eq!(buf[..4], b"\x82\x7e\x01\x00"[..]);
+        assert_eq!(buf.len(), 4 + payload.len());
+    }
+
+    fn masked_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1u8, 2, 3, 4];
+        let mut frame = vec![
+            (if fin { 0x80 } else { 0 }) | opcode,
+            0x80 | payload.len() as u8,
+        ];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    #[test]
+    fn test_fragmented_message() {
+        use std::io::{BufRead, BufReader};
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let request = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        client.write_all(request.as_bytes()).unwrap();
+        // a ping is sent between the fragments of a text message
+        client
+            .write_all(&masked_frame(false, OPCODE_TEXT, b"[1, "))
+            .unwrap();
+        client
+            .write_all(&masked_frame(true, OPCODE_PING, b"ping"))
+            .unwrap();
+        client
+            .write_all(&masked_frame(true, OPCODE_CONTINUATION, b"2]"))
+            .unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let websocket = WebSocket::accept(ClientStream::Tcp(stream)).unwrap();
+        let mut line = String::new();
+        BufReader::new(websocket).read_line(&mut line).unwrap();
+        assert_eq!(line, "[1, 2]\n");
+
+        let mut reader = BufReader::new(client);
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            reader.read_line(&mut response).unwrap();
+        }
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        let mut pong = [0u8; 6];
+        reader.read_exact(&mut pong).unwrap();
+        assert_eq!(&pong, b"\x8a\x04ping");
+    }
+}
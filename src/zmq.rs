@@ -0,0 +1,355 @@
+//! A minimal ZeroMQ subscriber (ZMTP 3.0 over TCP, using the NULL security mechanism),
+//! for receiving bitcoind's `zmqpubhashblock` and `zmqpubrawtx` notifications.
+
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::hash_types::BlockHash;
+use bitcoin::hashes::Hash;
+use error_chain::ChainedError;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60); // a PING is sent after being idle
+const MAX_FRAME_SIZE: u64 = 64 * 1024 * 1024; // larger than any (serialized) transaction
+
+const NULL_MECHANISM: [u8; 20] = *b"NULL\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+pub const TOPIC_HASHBLOCK: &str = "hashblock";
+pub const TOPIC_RAWTX: &str = "rawtx";
+
+pub enum Notification {
+    Block(BlockHash),
+    Transaction(Transaction),
+}
+
+fn greeting() -> Vec<u8> {
+    let mut greeting = vec![0u8; 64];
+    greeting[0] = 0xFF; // signature
+    greeting[9] = 0x7F;
+    greeting[10] = 3; // version 3.0
+    greeting[12..32].copy_from_slice(&NULL_MECHANISM);
+    greeting // as-server = 0, filler = 0
+}
+
+fn check_greeting(greeting: &[u8]) -> Result<()> {
+    if greeting[0] != 0xFF || greeting[9] != 0x7F {
+        bail!("invalid ZMTP signature");
+    }
+    if greeting[10] < 3 {
+        bail!("unsupported ZMTP version {}.{}", greeting[10], greeting[11]);
+    }
+    if greeting[12..32] != NULL_MECHANISM {
+        bail!("unsupported ZMTP mechanism");
+    }
+    Ok(())
+}
+
+/// Encodes a NULL-mechanism READY command body, announcing our socket type.
+fn ready_command(socket_type: &str) -> Vec<u8> {
+    let mut body = vec![5];
+    body.extend_from_slice(b"READY");
+    body.push(11);
+    body.extend_from_slice(b"Socket-Type");
+    body.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    body.extend_from_slice(socket_type.as_bytes());
+    body
+}
+
+/// Encodes a PING command body (ZMTP 3.1 heartbeat), without a TTL or context.
+fn ping_command() -> Vec<u8> {
+    let mut body = vec![4];
+    body.extend_from_slice(b"PING");
+    body.extend_from_slice(&0u16.to_be_bytes());
+    body
+}
+
+fn write_frame(stream: &mut dyn Write, flags: u8, body: &[u8]) -> Result<()> {
+    let mut frame = vec![];
+    if body.len() > 255 {
+        frame.push(flags | FLAG_LONG);
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        frame.push(flags);
+        frame.push(body.len() as u8);
+    }
+    frame.extend_from_slice(body);
+    stream
+        .write_all(&frame)
+        .chain_err(|| "failed to write ZMTP frame")
+}
+
+/// Returns the frame's flags and body.
+fn read_frame(stream: &mut dyn Read) -> Result<(u8, Vec<u8>)> {
+    let mut flags = [0u8; 1];
+    stream
+        .read_exact(&mut flags)
+        .chain_err(|| "failed to read ZMTP frame")?;
+    let flags = flags[0];
+    let size = if flags & FLAG_LONG != 0 {
+        let mut size = [0u8; 8];
+        stream
+            .read_exact(&mut size)
+            .chain_err(|| "failed to read ZMTP frame size")?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8; 1];
+        stream
+            .read_exact(&mut size)
+            .chain_err(|| "failed to read ZMTP frame size")?;
+        u64::from(size[0])
+    };
+    if size > MAX_FRAME_SIZE {
+        bail!("ZMTP frame is too large: {} bytes", size);
+    }
+    let mut body = vec![0u8; size as usize];
+    stream
+        .read_exact(&mut body)
+        .chain_err(|| "failed to read ZMTP frame body")?;
+    Ok((flags, body))
+}
+
+/// A ZMQ SUB socket, connected to a single publisher.
+struct Subscriber {
+    stream: TcpStream,
+}
+
+impl Subscriber {
+    fn connect(addr: SocketAddr, topics: &[&str], idle_timeout: Duration) -> Result<Subscriber> {
+        let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .chain_err(|| format!("failed to connect to {}", addr))?;
+        stream
+            .set_read_timeout(Some(idle_timeout))
+            .chain_err(|| "failed to set read timeout")?;
+        stream
+            .set_write_timeout(Some(idle_timeout))
+            .chain_err(|| "failed to set write timeout")?;
+        stream
+            .write_all(&greeting())
+            .chain_err(|| "failed to send ZMTP greeting")?;
+        let mut peer_greeting = [0u8; 64];
+        stream
+            .read_exact(&mut peer_greeting)
+            .chain_err(|| "failed to receive ZMTP greeting")?;
+        check_greeting(&peer_greeting)?;
+
+        write_frame(&mut stream, FLAG_COMMAND, &ready_command("SUB"))?;
+        let (flags, body) = read_frame(&mut stream)?;
+        if flags & FLAG_COMMAND == 0 || !body.starts_with(b"\x05READY") {
+            bail!("unexpected ZMTP handshake reply: {}", hex::encode(&body));
+        }
+        for topic in topics {
+            // ZMTP 3.0 subscriptions are sent as messages, prefixed by 0x01
+            let mut subscription = vec![1];
+            subscription.extend_from_slice(topic.as_bytes());
+            write_frame(&mut stream, 0, &subscription)?;
+        }
+        Ok(Subscriber { stream })
+    }
+
+    /// Waits for the next frame, sending a PING if the connection is idle. Fails if nothing
+    /// (not even a PONG) is received afterwards, so a half-open connection is re-connected.
+    fn wait(&mut self) -> Result<()> {
+        let mut pinged = false;
+        loop {
+            match self.stream.peek(&mut [0u8; 1]) {
+                Ok(0) => bail!("ZMQ connection closed"),
+                Ok(_) => return Ok(()),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if pinged {
+                        bail!("no ZMTP frames received after PING");
+                    }
+                    write_frame(&mut self.stream, FLAG_COMMAND, &ping_command())?;
+                    pinged = true;
+                }
+                Err(e) => return Err(e).chain_err(|| "failed to read ZMTP frame"),
+            }
+        }
+    }
+
+    /// Returns the next message's parts (skipping commands, e.g. heartbeats).
+    fn recv(&mut self) -> Result<Vec<Vec<u8>>> {
+        let mut parts = vec![];
+        loop {
+            self.wait()?;
+            let (flags, body) = read_frame(&mut self.stream)?;
+            if flags & FLAG_COMMAND != 0 {
+                continue;
+            }
+            parts.push(body);
+            if flags & FLAG_MORE == 0 {
+                return Ok(parts);
+            }
+        }
+    }
+}
+
+/// Parses bitcoind's [topic, body, sequence] messages (ignoring unknown topics).
+fn parse_notification(parts: &[Vec<u8>]) -> Result<Option<Notification>> {
+    if parts.len() < 2 {
+        bail!("invalid notification with {} parts", parts.len());
+    }
+    let (topic, body) = (&parts[0], &parts[1]);
+    let notification = match &topic[..] {
+        b"hashblock" => {
+            let mut hash = body.clone();
+            hash.reverse(); // published in RPC (reversed) byte order
+            Notification::Block(BlockHash::from_slice(&hash).chain_err(|| "invalid blockhash")?)
+        }
+        b"rawtx" => Notification::Transaction(deserialize(body).chain_err(|| "invalid tx")?),
+        _ => return Ok(None),
+    };
+    Ok(Some(notification))
+}
+
+fn subscribe(addr: SocketAddr, topics: &[&str], handler: &dyn Fn(Notification)) -> Result<()> {
+    let mut subscriber = Subscriber::connect(addr, topics, IDLE_TIMEOUT)?;
+    info!("subscribed to {:?} at {}", topics, addr);
+    loop {
+        let parts = subscriber.recv()?;
+        match parse_notification(&parts) {
+            Ok(Some(notification)) => handler(notification),
+            Ok(None) => (),
+            Err(e) => warn!("ignoring notification: {}", e.display_chain()),
+        }
+    }
+}
+
+/// Subscribes to the given (address, topic) pairs, in a background thread per address.
+/// Publishers are re-connected if needed, so notifications are received after bitcoind restarts.
+pub fn start<F>(endpoints: &[(SocketAddr, &'static str)], handler: F)
+where
+    F: Fn(Notification) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let mut topics_by_addr = HashMap::<SocketAddr, Vec<&'static str>>::new();
+    for (addr, topic) in endpoints {
+        topics_by_addr
+            .entry(*addr)
+            .or_insert_with(Vec::new)
+            .push(*topic);
+    }
+    for (addr, topics) in topics_by_addr {
+        let handler = Arc::clone(&handler);
+        spawn_thread("zmq", move || loop {
+            if let Err(e) = subscribe(addr, &topics, &*handler) {
+                warn!("ZMQ subscription to {} failed: {}", addr, e.display_chain());
+            }
+            thread::sleep(RECONNECT_DELAY);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::network::constants::Network;
+    use crossbeam_channel as channel;
+    use std::net::TcpListener;
+
+    // Stands in for bitcoind, accepting a single subscriber.
+    fn handshake(listener: TcpListener) -> TcpStream {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut peer_greeting = [0u8; 64];
+        stream.read_exact(&mut peer_greeting).unwrap();
+        check_greeting(&peer_greeting).unwrap();
+        stream.write_all(&greeting()).unwrap();
+
+        let (flags, body) = read_frame(&mut stream).unwrap();
+        assert_eq!(flags, FLAG_COMMAND);
+        assert_eq!(body, ready_command("SUB"));
+        write_frame(&mut stream, FLAG_COMMAND, &ready_command("PUB")).unwrap();
+
+        let mut subscriptions = vec![];
+        for _ in 0..2 {
+            subscriptions.push(read_frame(&mut stream).unwrap().1);
+        }
+        assert_eq!(
+            subscriptions,
+            vec![b"\x01hashblock".to_vec(), b"\x01rawtx".to_vec()]
+        );
+        stream
+    }
+
+    // Publishes a block and a transaction to a single subscriber.
+    fn publish(listener: TcpListener, messages: Vec<Vec<Vec<u8>>>) {
+        let mut stream = handshake(listener);
+        for parts in messages {
+            let last = parts.len() - 1;
+            for (i, part) in parts.iter().enumerate() {
+                let flags = if i < last { FLAG_MORE } else { 0 };
+                write_frame(&mut stream, flags, part).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let block = genesis_block(Network::Regtest);
+        let mut hash = block.block_hash().into_inner().to_vec();
+        hash.reverse();
+        let messages = vec![
+            vec![b"hashblock".to_vec(), hash, vec![0, 0, 0, 0]],
+            vec![b"unknown".to_vec(), vec![], vec![0, 0, 0, 0]],
+            vec![
+                b"rawtx".to_vec(),
+                serialize(&block.txdata[0]),
+                vec![0, 0, 0, 0],
+            ],
+        ];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let publisher = thread::spawn(move || publish(listener, messages));
+
+        let (sender, receiver) = channel::unbounded();
+        let handler = move |notification| sender.send(notification).unwrap();
+        start(&[(addr, TOPIC_HASHBLOCK), (addr, TOPIC_RAWTX)], handler);
+        publisher.join().unwrap();
+
+        match receiver.recv().unwrap() {
+            Notification::Block(blockhash) => assert_eq!(blockhash, block.block_hash()),
+            _ => panic!("expected a block notification"),
+        }
+        match receiver.recv().unwrap() {
+            Notification::Transaction(tx) => assert_eq!(tx.txid(), block.txdata[0].txid()),
+            _ => panic!("expected a transaction notification"),
+        }
+    }
+
+    #[test]
+    fn test_idle_publisher() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let publisher = thread::spawn(move || {
+            let mut stream = handshake(listener);
+            assert_eq!(
+                read_frame(&mut stream).unwrap(),
+                (FLAG_COMMAND, ping_command())
+            );
+            stream // stays open (without replying), like a half-open connection
+        });
+        let topics = [TOPIC_HASHBLOCK, TOPIC_RAWTX];
+        let mut subscriber =
+            Subscriber::connect(addr, &topics, Duration::from_millis(100)).unwrap();
+        let err = subscriber.recv().unwrap_err();
+        assert_eq!(err.to_string(), "no ZMTP frames received after PING");
+        publisher.join().unwrap();
+    }
+}